@@ -0,0 +1,202 @@
+use bevy::prelude::*;
+use bevy_prototype_schedule_states::{
+    driver, NextState, ScheduleState, ScheduleStates, StateData, StateIndex,
+};
+use criterion::{
+    criterion_group, criterion_main, measurement::WallTime, BenchmarkGroup, Criterion,
+};
+use std::hash::Hash;
+
+/// a state type per machine, so every machine gets its own driver
+#[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+enum Machine<const N: usize> {
+    A,
+    B,
+}
+
+impl<const N: usize> StateIndex for Machine<N> {
+    const COUNT: usize = 2;
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// the same machines as `Machine<N>`, with the `StateIndex` that
+/// `#[derive(ScheduleState)]` implements
+macro_rules! derived_machines {
+    ($($m: ident),*) => {
+        $(
+            mod $m {
+                use super::*;
+
+                #[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+                pub enum Derived {
+                    A,
+                    B,
+                }
+            }
+        )*
+    };
+}
+
+derived_machines!(
+    d0, d1, d2, d3, d4, d5, d6, d7, d8, d9, d10, d11, d12, d13, d14, d15, d16, d17, d18, d19, d20,
+    d21, d22, d23, d24, d25, d26, d27, d28, d29, d30, d31
+);
+
+/// what the enter and exit `Schedule`s of the states in the transition loop have
+#[derive(Clone, Copy)]
+enum EnterExit {
+    /// a system that does nothing
+    Systems,
+    /// no systems, so they are skipped
+    Empty,
+    /// the `Schedule`s don't exist
+    Missing,
+}
+
+/// number of transitions left in the current run of the transition loop
+struct TransitionsLeft(u32);
+
+fn insert_machine<S>(world: &mut World, stage: &mut SystemStage, states: ScheduleStates<S>)
+where
+    S: StateData,
+{
+    world.insert_resource(states);
+    world.insert_resource(NextState::<S>::default());
+    stage.add_system(driver::<S>.exclusive_system());
+}
+
+/// a machine with the states `a` and `b` that both have an update system
+fn machine<S>(indexed: bool, a: S, b: S) -> ScheduleStates<S>
+where
+    S: StateData<Key = S> + StateIndex,
+{
+    let mut states = if indexed {
+        ScheduleStates::new_indexed(a.clone())
+    } else {
+        ScheduleStates::new(a.clone())
+    };
+    states.with_state_update(a).add_system(|| {});
+    states.with_state_update(b).add_system(|| {});
+    states
+}
+
+macro_rules! insert_machines {
+    ($world: expr, $stage: expr, $indexed: expr, $($n: literal),*) => {
+        $(insert_machine($world, $stage, machine($indexed, Machine::<$n>::A, Machine::<$n>::B));)*
+    };
+}
+
+macro_rules! insert_derived_machines {
+    ($world: expr, $stage: expr, $($m: ident),*) => {
+        $(insert_machine($world, $stage, machine(true, $m::Derived::A, $m::Derived::B));)*
+    };
+}
+
+/// runs the drivers of 32 machines that don't change state
+fn many_machines(c: &mut Criterion) {
+    let mut group = c.benchmark_group("many_machines");
+    for (name, indexed) in [("hashed", false), ("indexed", true)] {
+        let mut world = World::new();
+        let mut stage = SystemStage::single_threaded();
+        insert_machines!(
+            &mut world, &mut stage, indexed, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+            16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31
+        );
+        stage.run(&mut world);
+
+        group.bench_function(name, |b| b.iter(|| stage.run(&mut world)));
+    }
+
+    // indexed by the derived `StateIndex`
+    let mut world = World::new();
+    let mut stage = SystemStage::single_threaded();
+    insert_derived_machines!(
+        &mut world, &mut stage, d0, d1, d2, d3, d4, d5, d6, d7, d8, d9, d10, d11, d12, d13, d14,
+        d15, d16, d17, d18, d19, d20, d21, d22, d23, d24, d25, d26, d27, d28, d29, d30, d31
+    );
+    stage.run(&mut world);
+    group.bench_function("derived", |b| b.iter(|| stage.run(&mut world)));
+    group.finish();
+}
+
+/// creates a machine whose driver switches between `a` and `b` 100 times per call
+fn transition_loop_world<S>(
+    indexed: bool,
+    enter_exit: EnterExit,
+    a: S,
+    b: S,
+) -> (World, SystemStage)
+where
+    S: StateData<Key = S> + StateIndex,
+{
+    let mut world = World::new();
+    let mut stage = SystemStage::single_threaded();
+    let mut states = machine(indexed, a.clone(), b.clone());
+    for (from, to) in [(a.clone(), b.clone()), (b, a)] {
+        states.with_state_update(from.clone()).add_system(
+            move |mut left: ResMut<TransitionsLeft>, mut next_state: ResMut<NextState<S>>| {
+                if left.0 > 0 {
+                    left.0 -= 1;
+                    next_state.set(to.clone());
+                }
+            },
+        );
+        match enter_exit {
+            EnterExit::Systems => {
+                states.with_state_enter(from.clone()).add_system(|| {});
+                states.with_state_exit(from).add_system(|| {});
+            }
+            EnterExit::Empty => {
+                states.with_state_enter(from.clone());
+                states.with_state_exit(from);
+            }
+            EnterExit::Missing => {}
+        }
+    }
+    insert_machine(&mut world, &mut stage, states);
+    world.insert_resource(TransitionsLeft(0));
+    stage.run(&mut world);
+    (world, stage)
+}
+
+/// one driver call that switches between two states 100 times
+fn transition_loop(c: &mut Criterion) {
+    let mut group = c.benchmark_group("transition_loop");
+    let configs = [
+        ("hashed", false, EnterExit::Systems),
+        ("indexed", true, EnterExit::Systems),
+        // compared with `update_only`, this shows the cost of the empty schedules that
+        // are skipped
+        ("empty_enter_exit", false, EnterExit::Empty),
+        ("update_only", false, EnterExit::Missing),
+    ];
+    for (name, indexed, enter_exit) in configs {
+        let (world, stage) =
+            transition_loop_world(indexed, enter_exit, Machine::<0>::A, Machine::<0>::B);
+        bench_transition_loop(&mut group, name, world, stage);
+    }
+    let (world, stage) =
+        transition_loop_world(true, EnterExit::Systems, d0::Derived::A, d0::Derived::B);
+    bench_transition_loop(&mut group, "derived", world, stage);
+    group.finish();
+}
+
+fn bench_transition_loop(
+    group: &mut BenchmarkGroup<WallTime>,
+    name: &str,
+    mut world: World,
+    mut stage: SystemStage,
+) {
+    group.bench_function(name, |b| {
+        b.iter(|| {
+            world.get_resource_mut::<TransitionsLeft>().unwrap().0 = 100;
+            stage.run(&mut world);
+        })
+    });
+}
+
+criterion_group!(benches, many_machines, transition_loop);
+criterion_main!(benches);
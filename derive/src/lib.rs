@@ -0,0 +1,132 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+/// Implements `ScheduleState`, `StateIndex` and `StateData` for an enum of states. A derive
+/// macro can't add other derives, so the enum still needs to derive the `PartialEq`, `Eq`,
+/// `Hash` and `Clone` traits that states need. The variants can't have fields. Mark the state
+/// `StatePlugin::default` starts in with `#[initial]`, otherwise it starts in the first one.
+#[proc_macro_derive(ScheduleState, attributes(initial))]
+pub fn derive_schedule_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match schedule_state(input) {
+        Ok(tokens) => tokens.into(),
+        Err(error) => error.to_compile_error().into(),
+    }
+}
+
+/// Implements `StateData` with the state as its own key, so every value of the type gets
+/// its own schedules. Use this for states that aren't enums of unit variants.
+#[proc_macro_derive(StateData)]
+pub fn derive_state_data(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    state_data(&input, None).into()
+}
+
+/// the `StateData` impl with `state_name` as the body of `StateData::name`, if it is given
+fn state_data(
+    input: &DeriveInput,
+    state_name: Option<proc_macro2::TokenStream>,
+) -> proc_macro2::TokenStream {
+    let name = &input.ident;
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+    let crate_path = quote!(::bevy_prototype_schedule_states);
+    let name_fn = state_name.map(|state_name| {
+        quote! {
+            fn name(&self) -> &'static str {
+                #state_name
+            }
+        }
+    });
+
+    quote! {
+        impl #impl_generics #crate_path::StateData for #name #type_generics #where_clause {
+            type Key = Self;
+
+            fn key(&self) -> Self {
+                ::core::clone::Clone::clone(self)
+            }
+
+            #name_fn
+        }
+    }
+}
+
+fn schedule_state(input: DeriveInput) -> Result<proc_macro2::TokenStream, Error> {
+    let name = &input.ident;
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "ScheduleState can't be derived for generic types",
+        ));
+    }
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(Error::new(
+                Span::call_site(),
+                "ScheduleState can only be derived for enums",
+            ))
+        }
+    };
+    if data.variants.is_empty() {
+        return Err(Error::new_spanned(
+            name,
+            "ScheduleState needs at least one variant",
+        ));
+    }
+
+    let mut initial = None;
+    for variant in data.variants.iter() {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(
+                &variant.fields,
+                "ScheduleState variants can't have fields",
+            ));
+        }
+        for attr in variant.attrs.iter().filter(|a| a.path.is_ident("initial")) {
+            if initial.is_some() {
+                return Err(Error::new_spanned(
+                    attr,
+                    "only one variant can be #[initial]",
+                ));
+            }
+            initial = Some(&variant.ident);
+        }
+    }
+    let initial = initial.unwrap_or(&data.variants[0].ident);
+
+    let variants: Vec<_> = data.variants.iter().map(|v| &v.ident).collect();
+    let names: Vec<_> = variants.iter().map(|v| v.to_string()).collect();
+    let indices = 0..variants.len();
+    let count = variants.len();
+    let crate_path = quote!(::bevy_prototype_schedule_states);
+    let state_data = state_data(
+        &input,
+        Some(quote! {
+            match self {
+                #(#name::#variants => #names,)*
+            }
+        }),
+    );
+
+    Ok(quote! {
+        #state_data
+
+        impl #crate_path::ScheduleState for #name {
+            const VARIANTS: &'static [Self] = &[#(#name::#variants),*];
+            const INITIAL: Self = #name::#initial;
+        }
+
+        impl #crate_path::StateIndex for #name {
+            const COUNT: usize = #count;
+
+            fn index(&self) -> usize {
+                match self {
+                    #(#name::#variants => #indices,)*
+                }
+            }
+        }
+    })
+}
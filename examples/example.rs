@@ -1,5 +1,7 @@
 use bevy::{core::FixedTimestep, prelude::*};
-use bevy_prototype_schedule_states::{driver, AppStateHelpers, NextState, StatePlugin};
+use bevy_prototype_schedule_states::{
+    driver, AppStateHelpers, NextState, ScheduleState, StatePlugin,
+};
 
 fn main() {
     App::new()
@@ -9,7 +11,8 @@ fn main() {
         // make sure you add the `StatePlugin` before trying to use the builder methods
         // on the state.  Failing to do so will cause a panic as the `ScheduleStates`
         // resource will not be available
-        .add_plugin(StatePlugin::<States>::new(States::StateA))
+        // `default` starts in the state marked with `#[initial]`
+        .add_plugin(StatePlugin::<States>::default())
         // add the state driver into your main schedule whereever you want it to be
         // You can add a FixedTimestep run criteria onto the driver to run all systems
         // inside the state at a fixed timestep.
@@ -29,19 +32,19 @@ fn main() {
         )
         .add_system_to_exit(States::StateA, || println!("exit state a"))
         .add_system_to_enter(States::StateB, || println!("enter state b"))
-        .add_system_set_to_update(
-            States::StateB,
-            SystemSet::new()
-                .with_system(|| println!("update state b"))
-                .with_system(change_state_b_to_a),
-        )
+        .add_system_to_update(States::StateB, || println!("update state b"))
+        // systems that return `Option<States>` can be added with
+        // `add_transition_system_to_update`. The returned state is queued as the next state.
+        .add_transition_system_to_update(States::StateB, change_state_b_to_a)
         .add_system_to_exit(States::StateB, || println!("exit state b"))
         .run();
 }
 
-// States are typically an simple enum and need some traits defined to work properly
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+// States are typically an simple enum and need some traits defined to work properly.
+// Deriving `ScheduleState` lets `StatePlugin::default` start in the `#[initial]` state.
+#[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 enum States {
+    #[initial]
     StateA,
     StateB,
 }
@@ -60,10 +63,13 @@ fn change_state_a_to_b(mut next_state: ResMut<NextState<States>>, mut count: Loc
     }
 }
 
-fn change_state_b_to_a(mut next_state: ResMut<NextState<States>>, mut count: Local<u32>) {
+// returning `Some(state)` changes the state the same way calling `NextState::set` does
+fn change_state_b_to_a(mut count: Local<u32>) -> Option<States> {
     *count += 1;
     if *count > 2 {
-        next_state.set(States::StateA);
         *count = 0;
+        Some(States::StateA)
+    } else {
+        None
     }
 }
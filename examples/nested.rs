@@ -3,7 +3,7 @@
 /// The bevy window will need focus to detect the keystrokes, but output is to the console.
 use bevy::{core::FixedTimestep, prelude::*};
 use bevy_prototype_schedule_states::{
-    driver, AppStateHelpers, NextState, ScheduleStates, StatePlugin,
+    driver, AppStateHelpers, NextState, ScheduleState, ScheduleStates, StatePlugin,
 };
 
 fn main() {
@@ -36,13 +36,13 @@ fn main() {
         .run();
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 enum GameState {
     StartMenu,
     Playing,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 enum PlayingState {
     Running,
     Paused,
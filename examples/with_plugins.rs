@@ -4,7 +4,7 @@ use bevy::{
     prelude::{App, ExclusiveSystemDescriptorCoercion, IntoExclusiveSystem, Plugin},
     DefaultPlugins,
 };
-use bevy_prototype_schedule_states::{driver, AppStateHelpers, StatePlugin};
+use bevy_prototype_schedule_states::{driver, AppStateHelpers, ScheduleState, StatePlugin};
 
 fn main() {
     App::new()
@@ -20,7 +20,7 @@ fn main() {
         .run();
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 enum States {
     StateA,
     StateB,
@@ -1,12 +1,19 @@
 use bevy::{
+    asset::HandleUntyped,
     ecs::schedule::IntoSystemDescriptor,
-    prelude::{App, SystemSet},
+    prelude::{App, IntoChainSystem, IntoSystem, SystemSet},
+    utils::Duration,
 };
-use std::hash::Hash;
 
-use crate::ScheduleStates;
+use crate::{
+    loading_state_system, set_enter_ready, set_next_state, CatchUpPolicy, LoadingHandles,
+    ScheduleStates, StateData, TransitionDelay,
+};
 
 /// a collection of functions for configuring schedule state resources added to the App.
+/// States are passed as values of `S` and their settings are stored under their
+/// `StateData::Key`. For states that carry data use the methods of `ScheduleStates`,
+/// which take the keys directly.
 pub trait AppStateHelpers {
     /// add a system to the update `Schedule` for `state`
     fn add_system_to_update<S, Params>(
@@ -15,7 +22,7 @@ pub trait AppStateHelpers {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
 
     /// add a system to the enter `Schedule` for `state`
     fn add_system_to_enter<S, Params>(
@@ -24,7 +31,7 @@ pub trait AppStateHelpers {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
 
     /// add a system to the exit `Schedule` for `state`
     fn add_system_to_exit<S, Params>(
@@ -33,28 +40,148 @@ pub trait AppStateHelpers {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
+
+    /// add a system that runs before the enter systems of every state of `S`
+    fn add_system_to_any_enter<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// add a system that runs before the update systems of every state of `S`
+    fn add_system_to_any_update<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// add a system that runs after the exit systems of every state of `S`
+    fn add_system_to_any_exit<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// add a system to the `Schedule` that runs every frame of the multi frame
+    /// transition from `from` to `to`
+    fn add_system_to_transition<S, Params>(
+        &mut self,
+        from: S,
+        to: S,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// add a system that returns `Option<S>` to the update `Schedule` for `state`.
+    /// The returned state is queued as the `NextState<S>`.
+    fn add_transition_system_to_update<S, Params>(
+        &mut self,
+        state: S,
+        system: impl IntoSystem<(), Option<S>, Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// add a system that returns `bool` to the enter `Schedule` for `state` and make
+    /// `state` enter until ready. The enter `Schedule` runs every time the driver runs
+    /// until the system returns true.
+    fn add_ready_system_to_enter<S, Params>(
+        &mut self,
+        state: S,
+        system: impl IntoSystem<(), bool, Params>,
+    ) -> &mut App
+    where
+        S: StateData;
 
     /// add a system set to the update `Schedule` for `state`
     fn add_system_set_to_update<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
 
     /// add a system set to the enter `Schedule` for `state`
     fn add_system_set_to_enter<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
 
     /// add a system set to the exit `Schedule` for `state`
     fn add_system_set_to_exit<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static;
+        S: StateData;
 
     /// add tne state drivers to a state `S` for `T`
     fn add_nested_driver_to_state<S, T>(&mut self, state: S) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
-        T: Eq + Hash + Copy + Send + Sync + 'static;
+        S: StateData,
+        T: StateData;
+
+    /// transition from `from` to `to` after `from` has been active for `delay`.
+    /// `delay` can be a `Duration` or a number of update ticks.
+    fn add_timed_transition<S>(
+        &mut self,
+        from: S,
+        to: S,
+        delay: impl Into<TransitionDelay>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// run the update `Schedule` of `state` once for every `step` of time that passed
+    /// instead of once per driver call
+    fn set_fixed_timestep<S>(&mut self, state: S, step: Duration) -> &mut App
+    where
+        S: StateData;
+
+    /// limit the number of times the update `Schedule` of `S` can run per driver call
+    fn set_update_budget<S>(&mut self, max_updates: u32, policy: CatchUpPolicy) -> &mut App
+    where
+        S: StateData;
+
+    /// add a guard system that needs to return true for the transition from `from`
+    /// to `to` to be applied
+    fn add_transition_guard<S, Params>(
+        &mut self,
+        from: S,
+        to: S,
+        guard: impl IntoSystem<(), bool, Params>,
+    ) -> &mut App
+    where
+        S: StateData;
+
+    /// make `loading` wait for its asset handles to load. Once all of them are loaded
+    /// the state changes to `loaded` and if any of them fail to load it changes to `failed`.
+    /// This requires bevy's `AssetPlugin`.
+    fn add_loading_state<S>(&mut self, loading: S, loaded: S, failed: S) -> &mut App
+    where
+        S: StateData;
+
+    /// add a handle that the loading state `state` waits on. States that carry data
+    /// share the handles of their key.
+    fn add_loading_handle<S>(&mut self, state: S, handle: HandleUntyped) -> &mut App
+    where
+        S: StateData;
+
+    /// transition from `from` to `to` when an event of type `E` is read while in `from`
+    fn add_event_transition<E, S>(&mut self, from: S, to: S) -> &mut App
+    where
+        E: Send + Sync + 'static,
+        S: StateData;
+
+    /// transition from `from` to `to` when an event of type `E` that `predicate` returns
+    /// true for is read while in `from`
+    fn add_event_transition_with<E, S>(
+        &mut self,
+        from: S,
+        to: S,
+        predicate: impl Fn(&E) -> bool + Send + Sync + 'static,
+    ) -> &mut App
+    where
+        E: Send + Sync + 'static,
+        S: StateData;
 }
 
 impl AppStateHelpers for App {
@@ -64,12 +191,12 @@ impl AppStateHelpers for App {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_update(state)
+            .with_state_update(state.key())
             .add_system(system);
 
         self
@@ -81,12 +208,12 @@ impl AppStateHelpers for App {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_enter(state)
+            .with_state_enter(state.key())
             .add_system(system);
 
         self
@@ -98,25 +225,125 @@ impl AppStateHelpers for App {
         system: impl IntoSystemDescriptor<Params>,
     ) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_state_exit(state.key())
+            .add_system(system);
+
+        self
+    }
+
+    fn add_system_to_any_enter<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_exit(state)
+            .with_any_state_enter()
             .add_system(system);
 
         self
     }
 
+    fn add_system_to_any_update<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_any_state_update()
+            .add_system(system);
+
+        self
+    }
+
+    fn add_system_to_any_exit<S, Params>(
+        &mut self,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_any_state_exit()
+            .add_system(system);
+
+        self
+    }
+
+    fn add_system_to_transition<S, Params>(
+        &mut self,
+        from: S,
+        to: S,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_transition_update(from.key(), to.key())
+            .add_system(system);
+
+        self
+    }
+
+    fn add_transition_system_to_update<S, Params>(
+        &mut self,
+        state: S,
+        system: impl IntoSystem<(), Option<S>, Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_state_update(state.key())
+            .add_system(system.chain(set_next_state::<S>));
+
+        self
+    }
+
+    fn add_ready_system_to_enter<S, Params>(
+        &mut self,
+        state: S,
+        system: impl IntoSystem<(), bool, Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        let mut states = self.world.get_resource_mut::<ScheduleStates<S>>().unwrap();
+        states.enter_until_ready(state.key());
+        states
+            .with_state_enter(state.key())
+            .add_system(system.chain(set_enter_ready::<S>));
+
+        self
+    }
+
     fn add_system_set_to_update<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_update(state)
+            .with_state_update(state.key())
             .add_system_set(system_set);
 
         self
@@ -124,12 +351,12 @@ impl AppStateHelpers for App {
 
     fn add_system_set_to_enter<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_enter(state)
+            .with_state_enter(state.key())
             .add_system_set(system_set);
 
         self
@@ -137,12 +364,12 @@ impl AppStateHelpers for App {
 
     fn add_system_set_to_exit<S>(&mut self, state: S, system_set: SystemSet) -> &mut App
     where
-        S: Copy + Clone + Send + Sync + Eq + Hash + 'static,
+        S: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .with_state_exit(state)
+            .with_state_exit(state.key())
             .add_system_set(system_set);
 
         self
@@ -150,13 +377,129 @@ impl AppStateHelpers for App {
 
     fn add_nested_driver_to_state<S, T>(&mut self, state: S) -> &mut App
     where
-        S: Eq + Hash + Copy + Send + Sync + Hash + 'static,
-        T: Eq + Hash + Copy + Send + Sync + 'static,
+        S: StateData,
+        T: StateData,
     {
         self.world
             .get_resource_mut::<ScheduleStates<S>>()
             .unwrap()
-            .add_nested_driver_to_state::<T>(state);
+            .add_nested_driver_to_state::<T>(state.key());
+
+        self
+    }
+
+    fn add_timed_transition<S>(
+        &mut self,
+        from: S,
+        to: S,
+        delay: impl Into<TransitionDelay>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .add_timed_transition(from.key(), to, delay);
+
+        self
+    }
+
+    fn set_fixed_timestep<S>(&mut self, state: S, step: Duration) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .set_fixed_timestep(state.key(), step);
+
+        self
+    }
+
+    fn set_update_budget<S>(&mut self, max_updates: u32, policy: CatchUpPolicy) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .set_update_budget(max_updates, policy);
+
+        self
+    }
+
+    fn add_event_transition<E, S>(&mut self, from: S, to: S) -> &mut App
+    where
+        E: Send + Sync + 'static,
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .add_event_transition::<E>(from.key(), to);
+
+        self
+    }
+
+    fn add_event_transition_with<E, S>(
+        &mut self,
+        from: S,
+        to: S,
+        predicate: impl Fn(&E) -> bool + Send + Sync + 'static,
+    ) -> &mut App
+    where
+        E: Send + Sync + 'static,
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .add_event_transition_with(from.key(), to, predicate);
+
+        self
+    }
+
+    fn add_transition_guard<S, Params>(
+        &mut self,
+        from: S,
+        to: S,
+        guard: impl IntoSystem<(), bool, Params>,
+    ) -> &mut App
+    where
+        S: StateData,
+    {
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .add_transition_guard(from.key(), to.key(), guard);
+
+        self
+    }
+
+    fn add_loading_state<S>(&mut self, loading: S, loaded: S, failed: S) -> &mut App
+    where
+        S: StateData,
+    {
+        self.init_resource::<LoadingHandles<S>>();
+        self.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .with_state_update(loading.key())
+            .add_system(loading_state_system(loading, loaded, failed));
+
+        self
+    }
+
+    fn add_loading_handle<S>(&mut self, state: S, handle: HandleUntyped) -> &mut App
+    where
+        S: StateData,
+    {
+        self.init_resource::<LoadingHandles<S>>();
+        self.world
+            .get_resource_mut::<LoadingHandles<S>>()
+            .unwrap()
+            .add(state.key(), handle);
 
         self
     }
@@ -0,0 +1,168 @@
+use bevy::{
+    core::Time,
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::{App, CoreStage, Plugin, Res, ResMut},
+    utils::{Duration, HashMap},
+};
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
+
+use crate::{graph::short_type_name, snapshot::StableHasher, ScheduleStates, StateData};
+
+/// Counters the driver records while diagnostics are enabled for a `ScheduleStates`
+pub(crate) struct DriverStats<S> {
+    transitions: u32,
+    driver_calls: u32,
+    loop_iterations: u32,
+    update_time: HashMap<S, Duration>,
+}
+
+impl<S> Default for DriverStats<S> {
+    fn default() -> Self {
+        DriverStats {
+            transitions: 0,
+            driver_calls: 0,
+            loop_iterations: 0,
+            update_time: HashMap::default(),
+        }
+    }
+}
+
+impl<S: Eq + Hash> DriverStats<S> {
+    pub(crate) fn record_transition(&mut self) {
+        self.transitions += 1;
+    }
+
+    pub(crate) fn record_driver_call(&mut self, loop_iterations: u32) {
+        self.driver_calls += 1;
+        self.loop_iterations += loop_iterations;
+    }
+
+    pub(crate) fn record_update(&mut self, state: S, time: Duration) {
+        *self.update_time.entry(state).or_default() += time;
+    }
+}
+
+/// Adds `Diagnostic`s for the `ScheduleStates<S>` of a `StatePlugin<S>`: the number of
+/// transitions per second, the average number of times the driver loops per call and
+/// the time spent in the update `Schedule` of each state. Add this after the
+/// `StatePlugin<S>` and bevy's `DiagnosticsPlugin`.
+pub struct StateDiagnosticsPlugin<S> {
+    marker: PhantomData<S>,
+}
+
+impl<S> Default for StateDiagnosticsPlugin<S> {
+    fn default() -> Self {
+        StateDiagnosticsPlugin {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<S> Plugin for StateDiagnosticsPlugin<S>
+where
+    S: StateData,
+    S::Key: Debug,
+{
+    fn build(&self, app: &mut App) {
+        app.world
+            .get_resource_mut::<ScheduleStates<S>>()
+            .unwrap()
+            .enable_stats();
+        app.add_startup_system(Self::setup_system)
+            // the last stage so all the drivers that run this frame are measured
+            .add_system_to_stage(CoreStage::Last, Self::diagnostic_system);
+    }
+}
+
+impl<S> StateDiagnosticsPlugin<S>
+where
+    S: StateData,
+    S::Key: Debug,
+{
+    /// id of the `Diagnostic` for the number of transitions per second
+    pub fn transitions_per_second() -> DiagnosticId {
+        diagnostic_id::<S>("transitions_per_second", "")
+    }
+
+    /// id of the `Diagnostic` for the average number of times the driver loops per call
+    pub fn driver_loops() -> DiagnosticId {
+        diagnostic_id::<S>("driver_loops", "")
+    }
+
+    /// id of the `Diagnostic` for the time spent in the update `Schedule` of `state`
+    pub fn update_time(state: S::Key) -> DiagnosticId {
+        diagnostic_id::<S>("update_time", &format!("{:?}", state))
+    }
+
+    pub fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
+        let name = short_type_name(std::any::type_name::<S>());
+        diagnostics.add(Diagnostic::new(
+            Self::transitions_per_second(),
+            format!("{} transitions/s", name),
+            20,
+        ));
+        diagnostics.add(Diagnostic::new(
+            Self::driver_loops(),
+            format!("{} driver loops", name),
+            20,
+        ));
+    }
+
+    pub fn diagnostic_system(
+        mut diagnostics: ResMut<Diagnostics>,
+        mut states: ResMut<ScheduleStates<S>>,
+        time: Res<Time>,
+    ) {
+        let stats = match states.stats_mut() {
+            Some(stats) => std::mem::take(stats),
+            None => return,
+        };
+
+        if stats.driver_calls > 0 {
+            diagnostics.add_measurement(
+                Self::driver_loops(),
+                stats.loop_iterations as f64 / stats.driver_calls as f64,
+            );
+        }
+
+        for (state, update_time) in stats.update_time {
+            let id = Self::update_time(state.clone());
+            // states get their diagnostic the first time their update `Schedule` runs
+            if diagnostics.get(id).is_none() {
+                let name = format!(
+                    "{}::{:?} update",
+                    short_type_name(std::any::type_name::<S>()),
+                    state
+                );
+                diagnostics.add(Diagnostic::new(id, name, 20).with_suffix("s"));
+            }
+            diagnostics.add_measurement(id, update_time.as_secs_f64());
+        }
+
+        if time.delta_seconds_f64() == 0.0 {
+            return;
+        }
+        diagnostics.add_measurement(
+            Self::transitions_per_second(),
+            stats.transitions as f64 / time.delta_seconds_f64(),
+        );
+    }
+}
+
+/// creates a `DiagnosticId` that is the same on every run and unique for each state
+/// type, measurement and state
+fn diagnostic_id<S>(measurement: &str, state: &str) -> DiagnosticId {
+    let hash = |salt: u8| {
+        let mut hasher = StableHasher::default();
+        salt.hash(&mut hasher);
+        std::any::type_name::<S>().hash(&mut hasher);
+        measurement.hash(&mut hasher);
+        state.hash(&mut hasher);
+        hasher.finish() as u128
+    };
+    DiagnosticId::from_u128(hash(0) << 64 | hash(1))
+}
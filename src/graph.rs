@@ -0,0 +1,338 @@
+use bevy::{prelude::World, utils::HashMap};
+use std::{
+    any::TypeId,
+    collections::BTreeMap,
+    fmt::{Debug, Write},
+};
+
+use crate::{state_schedule::DeclaredTransition, NestedDriver, ScheduleStates, StateData};
+
+/// A diagram of state machines that can be written as Graphviz DOT or Mermaid. Shows
+/// the states of each machine with the number of systems in their enter, update and
+/// exit schedules, the declared and observed transitions, and which states have
+/// nested drivers.
+#[derive(Default)]
+pub struct StateGraph {
+    machines: Vec<MachineGraph>,
+}
+
+struct MachineGraph {
+    type_id: TypeId,
+    name: String,
+    states: Vec<StateNode>,
+    /// `(from, to)` state indices and the kinds of the transition
+    transitions: BTreeMap<(usize, usize), Vec<&'static str>>,
+}
+
+struct StateNode {
+    label: String,
+    current: bool,
+    /// number of systems in the enter, update and exit schedules
+    systems: [usize; 3],
+    nested_drivers: Vec<NestedDriver>,
+}
+
+impl StateNode {
+    fn systems_label(&self) -> String {
+        let [enter, update, exit] = self.systems;
+        format!("enter: {}, update: {}, exit: {}", enter, update, exit)
+    }
+}
+
+impl StateGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add the `ScheduleStates<S>` in `world` to the graph. Machines that are nested
+    /// in one of its states are drawn inside of that state if they are added too.
+    pub fn add_machine<S>(&mut self, world: &World) -> &mut Self
+    where
+        S: StateData,
+        S::Key: Debug,
+    {
+        let states = world.get_resource::<ScheduleStates<S>>().unwrap();
+
+        let mut known_states: Vec<(String, S::Key)> = states
+            .known_states()
+            .into_iter()
+            .map(|state| (format!("{:?}", state), state))
+            .collect();
+        // sort so the output is the same every time
+        known_states.sort_by(|a, b| a.0.cmp(&b.0));
+        let indices: HashMap<S::Key, usize> = known_states
+            .iter()
+            .enumerate()
+            .map(|(index, (_, state))| (state.clone(), index))
+            .collect();
+
+        let mut transitions: BTreeMap<(usize, usize), Vec<&'static str>> = BTreeMap::new();
+        for (from, to, kind) in states.declared_transitions() {
+            let kind = match kind {
+                DeclaredTransition::Timed => "timed",
+                DeclaredTransition::Event => "event",
+                DeclaredTransition::Guarded => "guarded",
+                DeclaredTransition::MultiFrame => "multi frame",
+            };
+            transitions
+                .entry((indices[&from], indices[&to]))
+                .or_default()
+                .push(kind);
+        }
+        for (from, to) in states.observed_transitions() {
+            transitions
+                .entry((indices[from], indices[to]))
+                .or_default()
+                .push("observed");
+        }
+        for kinds in transitions.values_mut() {
+            kinds.sort_unstable();
+            kinds.dedup();
+        }
+
+        let nodes = known_states
+            .into_iter()
+            .map(|(label, state)| StateNode {
+                label,
+                current: states.is_in(&state),
+                systems: states.system_counts(&state),
+                nested_drivers: states.nested_drivers(state).to_vec(),
+            })
+            .collect();
+
+        self.machines.push(MachineGraph {
+            type_id: TypeId::of::<S>(),
+            name: short_type_name(std::any::type_name::<S>()),
+            states: nodes,
+            transitions,
+        });
+        self
+    }
+
+    fn machine_index(&self, type_id: TypeId) -> Option<usize> {
+        self.machines
+            .iter()
+            .position(|machine| machine.type_id == type_id)
+    }
+
+    /// write the graph in the Graphviz DOT format
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+        writeln!(dot, "digraph states {{").unwrap();
+        writeln!(dot, "    compound=true;").unwrap();
+        writeln!(dot, "    node [shape=box, style=rounded];").unwrap();
+
+        for (m, machine) in self.machines.iter().enumerate() {
+            writeln!(dot, "    subgraph cluster_{} {{", m).unwrap();
+            writeln!(dot, "        label=\"{}\";", escape(&machine.name)).unwrap();
+            for (s, state) in machine.states.iter().enumerate() {
+                let bold = if state.current { ", penwidth=2" } else { "" };
+                writeln!(
+                    dot,
+                    "        m{}_s{} [label=\"{}\\n{}\"{}];",
+                    m,
+                    s,
+                    escape(&state.label),
+                    state.systems_label(),
+                    bold
+                )
+                .unwrap();
+            }
+            writeln!(dot, "    }}").unwrap();
+
+            for ((from, to), kinds) in machine.transitions.iter() {
+                writeln!(
+                    dot,
+                    "    m{}_s{} -> m{}_s{} [label=\"{}\"];",
+                    m,
+                    from,
+                    m,
+                    to,
+                    kinds.join(", ")
+                )
+                .unwrap();
+            }
+        }
+
+        // nested drivers point at the cluster of the nested machine or at a placeholder
+        // if that machine wasn't added to the graph
+        for (m, machine) in self.machines.iter().enumerate() {
+            for (s, state) in machine.states.iter().enumerate() {
+                for (n, nested) in state.nested_drivers.iter().enumerate() {
+                    match self.machine_index(nested.type_id()) {
+                        Some(nested_m) if !self.machines[nested_m].states.is_empty() => {
+                            writeln!(
+                                dot,
+                                "    m{}_s{} -> m{}_s0 [lhead=cluster_{}, style=dashed, label=\"nested\"];",
+                                m, s, nested_m, nested_m
+                            )
+                            .unwrap();
+                        }
+                        _ => {
+                            writeln!(
+                                dot,
+                                "    m{}_s{}_n{} [label=\"{}\", style=dashed];",
+                                m,
+                                s,
+                                n,
+                                escape(&short_type_name(nested.type_name()))
+                            )
+                            .unwrap();
+                            writeln!(
+                                dot,
+                                "    m{}_s{} -> m{}_s{}_n{} [style=dashed, label=\"nested\"];",
+                                m, s, m, s, n
+                            )
+                            .unwrap();
+                        }
+                    }
+                }
+            }
+        }
+
+        writeln!(dot, "}}").unwrap();
+        dot
+    }
+
+    /// write the graph as a Mermaid state diagram. Nested machines are drawn inside
+    /// of the state they are nested in.
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = String::new();
+        writeln!(mermaid, "stateDiagram-v2").unwrap();
+        writeln!(mermaid, "    classDef current font-weight:bold").unwrap();
+
+        let nested: Vec<usize> = self
+            .machines
+            .iter()
+            .flat_map(|machine| machine.states.iter())
+            .flat_map(|state| state.nested_drivers.iter())
+            .filter_map(|nested| self.machine_index(nested.type_id()))
+            .collect();
+        let mut visited = vec![false; self.machines.len()];
+        for m in 0..self.machines.len() {
+            if !nested.contains(&m) {
+                self.write_mermaid_machine(&mut mermaid, m, 1, &mut visited);
+            }
+        }
+        // machines that are only nested in each other
+        for m in 0..self.machines.len() {
+            if !visited[m] {
+                self.write_mermaid_machine(&mut mermaid, m, 1, &mut visited);
+            }
+        }
+
+        for (m, machine) in self.machines.iter().enumerate() {
+            for (s, state) in machine.states.iter().enumerate() {
+                if state.current {
+                    writeln!(mermaid, "    class m{}_s{} current", m, s).unwrap();
+                }
+            }
+        }
+        mermaid
+    }
+
+    fn write_mermaid_machine(
+        &self,
+        mermaid: &mut String,
+        m: usize,
+        depth: usize,
+        visited: &mut Vec<bool>,
+    ) {
+        visited[m] = true;
+        let machine = &self.machines[m];
+        let indent = "    ".repeat(depth);
+        writeln!(
+            mermaid,
+            "{}state \"{}\" as m{} {{",
+            indent,
+            escape(&machine.name),
+            m
+        )
+        .unwrap();
+
+        for (s, state) in machine.states.iter().enumerate() {
+            let inner = "    ".repeat(depth + 1);
+            if state.nested_drivers.is_empty() {
+                writeln!(
+                    mermaid,
+                    "{}state \"{}\" as m{}_s{}",
+                    inner,
+                    escape(&state.label),
+                    m,
+                    s
+                )
+                .unwrap();
+            } else {
+                writeln!(
+                    mermaid,
+                    "{}state \"{}\" as m{}_s{} {{",
+                    inner,
+                    escape(&state.label),
+                    m,
+                    s
+                )
+                .unwrap();
+                for (n, nested) in state.nested_drivers.iter().enumerate() {
+                    if n > 0 {
+                        writeln!(mermaid, "{}    --", inner).unwrap();
+                    }
+                    match self.machine_index(nested.type_id()) {
+                        Some(nested_m) if !visited[nested_m] => {
+                            self.write_mermaid_machine(mermaid, nested_m, depth + 2, visited);
+                        }
+                        _ => {
+                            writeln!(
+                                mermaid,
+                                "{}    state \"{}\" as m{}_s{}_n{}",
+                                inner,
+                                escape(&short_type_name(nested.type_name())),
+                                m,
+                                s,
+                                n
+                            )
+                            .unwrap();
+                        }
+                    }
+                }
+                writeln!(mermaid, "{}}}", inner).unwrap();
+            }
+            writeln!(
+                mermaid,
+                "{}m{}_s{} : {}",
+                inner,
+                m,
+                s,
+                state.systems_label()
+            )
+            .unwrap();
+        }
+
+        for ((from, to), kinds) in machine.transitions.iter() {
+            writeln!(
+                mermaid,
+                "{}    m{}_s{} --> m{}_s{} : {}",
+                indent,
+                m,
+                from,
+                m,
+                to,
+                kinds.join(", ")
+            )
+            .unwrap();
+        }
+        writeln!(mermaid, "{}}}", indent).unwrap();
+    }
+}
+
+/// strips the module path from a type name that has no generics
+pub(crate) fn short_type_name(type_name: &str) -> String {
+    if type_name.contains('<') {
+        type_name.to_string()
+    } else {
+        type_name.rsplit("::").next().unwrap().to_string()
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('"', "'")
+}
@@ -1,10 +1,36 @@
 mod app_helpers;
+mod diagnostics;
+mod graph;
+mod loading;
+mod snapshot;
+mod stage;
+mod state_data;
 mod state_schedule;
-use std::hash::Hash;
+mod storage;
+mod validation;
+
+// lets the code generated by the derive macros refer to this crate from inside of it
+extern crate self as bevy_prototype_schedule_states;
 
 pub use app_helpers::AppStateHelpers;
 use bevy::prelude::{App, Plugin};
-pub use state_schedule::{driver, NextState, ScheduleStates};
+pub use bevy_prototype_schedule_states_derive::{ScheduleState, StateData};
+pub use diagnostics::StateDiagnosticsPlugin;
+pub use graph::StateGraph;
+pub use loading::{loading_state_system, LoadingHandles};
+pub use snapshot::{restore_state, snapshot_state, SnapshotStates, StateSnapshot};
+pub use stage::{ParallelStatesStage, StateMachineStage};
+pub use state_data::{ScheduleState, StateData};
+pub use state_schedule::{
+    apply_transitions, driver, run_state_update, set_enter_ready, set_next_state, CatchUpPolicy,
+    CurrentState, EnterReady, GuardPolicy, InterpolationAlpha, NestedDriver, NextState,
+    ScheduleStates, TransitionBlocked, TransitionContext, TransitionDelay, TransitionProgress,
+    UpdateBudgetExceeded,
+};
+pub use storage::StateIndex;
+pub use validation::{
+    validate_states, StateValidationError, StateValidationPlugin, ValidationFailure,
+};
 
 /// This adds the `NextState` and `ScheduleStates` resources to Bevy.
 /// Drivers for the states should be configured separately. The driver
@@ -12,31 +38,79 @@ pub use state_schedule::{driver, NextState, ScheduleStates};
 /// should be user configurable.
 pub struct StatePlugin<S> {
     initial_state: S,
+    /// inserts the `ScheduleStates<S>` resource with the storage that was picked
+    insert_states: fn(&mut App, S),
 }
 
-impl<S> StatePlugin<S> {
+impl<S> StatePlugin<S>
+where
+    S: StateData,
+{
     pub fn new(initial_state: S) -> Self {
-        Self { initial_state }
+        Self {
+            initial_state,
+            insert_states: |app, initial_state| {
+                app.insert_resource(ScheduleStates::new(initial_state));
+            },
+        }
+    }
+
+    /// like `new`, but the `Schedule`s of the states are kept in a `Vec` indexed by
+    /// `StateIndex`. See `ScheduleStates::new_indexed`.
+    pub fn new_indexed(initial_state: S) -> Self
+    where
+        S::Key: StateIndex,
+    {
+        Self {
+            initial_state,
+            insert_states: |app, initial_state| {
+                app.insert_resource(ScheduleStates::new_indexed(initial_state));
+            },
+        }
+    }
+}
+
+/// starts in the state marked with `#[initial]` and keeps the `Schedule`s in a `Vec`
+impl<S> Default for StatePlugin<S>
+where
+    S: ScheduleState,
+{
+    fn default() -> Self {
+        Self::new_indexed(S::INITIAL)
     }
 }
 
 impl<S> Plugin for StatePlugin<S>
 where
-    S: Send + Sync + 'static + Eq + Hash + Copy + Clone,
+    S: StateData,
 {
     fn build(&self, app: &mut App) {
+        (self.insert_states)(app, self.initial_state.clone());
         app.insert_resource(NextState::<S>::default())
-            .insert_resource(ScheduleStates::<S>::new(self.initial_state));
+            .add_event::<TransitionBlocked<S>>()
+            .add_event::<UpdateBudgetExceeded<S>>();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use bevy::prelude::*;
+    use bevy::{
+        app::Events,
+        asset::{AssetIo, AssetIoError, AssetLoader, AssetPlugin, LoadContext, LoadedAsset},
+        ecs::schedule::ShouldRun,
+        prelude::*,
+        reflect::TypeUuid,
+        tasks::{ComputeTaskPool, TaskPool, TaskPoolBuilder},
+        utils::{BoxedFuture, Duration, Instant},
+    };
+    use std::{
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicU32, Ordering},
+    };
 
     use super::*;
 
-    #[derive(PartialEq, Eq, Hash, Clone, Copy)]
+    #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
     enum States {
         StateA,
         StateB,
@@ -119,4 +193,1543 @@ mod tests {
             }
         );
     }
+
+    fn current_state(world: &World) -> States {
+        world
+            .get_resource::<ScheduleStates<States>>()
+            .unwrap()
+            .current_state()
+    }
+
+    #[test]
+    fn timed_transition() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_timed_transition(States::StateA, States::StateB, 2);
+        states
+            .with_state_enter(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.updates += 1);
+        states
+            .with_state_exit(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.exits += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateACounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+
+        // leaving state a early cancels the timer
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateA);
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0,
+            Counts {
+                enters: 2,
+                updates: 3,
+                exits: 2
+            }
+        );
+    }
+
+    #[test]
+    fn timed_transition_cycle() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        // without a limit the driver would switch between the states forever
+        states.add_timed_transition(States::StateA, States::StateB, 0);
+        states.add_timed_transition(States::StateB, States::StateA, 0);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+    }
+
+    #[test]
+    fn transition_system() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_update(States::StateA)
+            .add_system((|| Some(States::StateB)).chain(set_next_state::<States>));
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(
+            world.get_resource::<StateBCounts>().unwrap().0,
+            Counts {
+                enters: 1,
+                updates: 1,
+                exits: 0
+            }
+        );
+    }
+
+    #[test]
+    fn event_transition() {
+        struct Toggle(bool);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_event_transition_with(States::StateA, States::StateB, |e: &Toggle| e.0);
+        states.add_event_transition::<Toggle>(States::StateB, States::StateA);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Events::<Toggle>::default());
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+
+        world
+            .get_resource_mut::<Events<Toggle>>()
+            .unwrap()
+            .send(Toggle(false));
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+
+        world
+            .get_resource_mut::<Events<Toggle>>()
+            .unwrap()
+            .send(Toggle(true));
+        stage.run(&mut world);
+        // the event was sent before state b was entered, so it doesn't trigger
+        // the transition back to a
+        assert!(current_state(&world) == States::StateB);
+
+        world
+            .get_resource_mut::<Events<Toggle>>()
+            .unwrap()
+            .send(Toggle(false));
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+    }
+
+    #[test]
+    fn guarded_transition() {
+        struct Saving(bool);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_transition_guard(States::StateA, States::StateB, |saving: Res<Saving>| {
+            !saving.0
+        });
+        states.set_guard_policy(GuardPolicy::Retry);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Events::<TransitionBlocked<States>>::default());
+        world.insert_resource(Saving(true));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+
+        let events = world
+            .get_resource::<Events<TransitionBlocked<States>>>()
+            .unwrap();
+        let blocked = events.get_reader().iter(events).count();
+        assert_eq!(blocked, 2);
+
+        world.get_resource_mut::<Saving>().unwrap().0 = false;
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+    }
+
+    #[test]
+    fn multi_frame_transition() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_transition_update(States::StateA, States::StateB)
+            .add_system(|mut progress: ResMut<TransitionProgress<States>>| {
+                let next = progress.progress() + 0.5;
+                progress.set_progress(next);
+            });
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        let states = world.get_resource::<ScheduleStates<States>>().unwrap();
+        assert!(states.transitioning() == Some((States::StateA, States::StateB)));
+        assert!(world.get_resource::<TransitionProgress<States>>().is_some());
+        assert_eq!(
+            world.get_resource::<StateBCounts>().unwrap().0,
+            Counts::default()
+        );
+
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert!(world.get_resource::<TransitionProgress<States>>().is_none());
+        assert_eq!(
+            world.get_resource::<StateBCounts>().unwrap().0,
+            Counts {
+                enters: 1,
+                updates: 1,
+                exits: 0
+            }
+        );
+    }
+
+    #[test]
+    fn enter_until_ready() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.enter_until_ready(States::StateA);
+        states.with_state_enter(States::StateA).add_system(
+            (|ready: Res<EnterReady<States>>| ready.frames() >= 2).chain(set_enter_ready::<States>),
+        );
+        states
+            .with_state_enter(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.updates += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateACounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        stage.run(&mut world);
+        assert!(world
+            .get_resource::<ScheduleStates<States>>()
+            .unwrap()
+            .is_entering());
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0,
+            Counts {
+                enters: 2,
+                updates: 0,
+                exits: 0
+            }
+        );
+
+        stage.run(&mut world);
+        assert!(world.get_resource::<EnterReady<States>>().is_none());
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0,
+            Counts {
+                enters: 3,
+                updates: 1,
+                exits: 0
+            }
+        );
+    }
+
+    #[test]
+    fn nested_states() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Nested {
+            Running,
+        }
+
+        let mut world = World::new();
+        let mut nested = ScheduleStates::new(Nested::Running);
+        nested
+            .with_state_enter(Nested::Running)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        nested
+            .with_state_update(Nested::Running)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(nested);
+        world.insert_resource(NextState::<Nested>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_nested_driver_to_state::<Nested>(States::StateA);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        stage.run(&mut world);
+        // the nested state is entered with the state it is nested in and not again by
+        // the first run of its driver
+        assert_eq!(
+            world.get_resource::<StateBCounts>().unwrap().0,
+            Counts {
+                enters: 1,
+                updates: 2,
+                exits: 0
+            }
+        );
+    }
+
+    #[test]
+    fn nested_enter_until_ready() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Nested {
+            Loading,
+        }
+
+        let mut world = World::new();
+        let mut nested = ScheduleStates::new(Nested::Loading);
+        nested.enter_until_ready(Nested::Loading);
+        nested.with_state_enter(Nested::Loading).add_system(
+            (|ready: Res<EnterReady<Nested>>| ready.frames() >= 2).chain(set_enter_ready::<Nested>),
+        );
+        nested
+            .with_state_enter(Nested::Loading)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        nested
+            .with_state_update(Nested::Loading)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(nested);
+        world.insert_resource(NextState::<Nested>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_nested_driver_to_state::<Nested>(States::StateA);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        // the enter of the state it is nested in and the nested driver both run the
+        // enter `Schedule` once
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        assert!(world
+            .get_resource::<ScheduleStates<Nested>>()
+            .unwrap()
+            .is_entering());
+        assert_eq!(
+            world.get_resource::<EnterReady<Nested>>().unwrap().frames(),
+            2
+        );
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.updates, 0);
+
+        stage.run(&mut world);
+        assert!(world.get_resource::<EnterReady<Nested>>().is_none());
+        assert_eq!(
+            world.get_resource::<StateBCounts>().unwrap().0,
+            Counts {
+                enters: 3,
+                updates: 1,
+                exits: 0
+            }
+        );
+    }
+
+    #[test]
+    fn nested_exit_during_transition() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Inner {
+            One,
+            Two,
+        }
+        struct Log(Vec<&'static str>);
+
+        let mut world = World::new();
+        let mut nested = ScheduleStates::new(Inner::One);
+        nested
+            .with_state_exit(Inner::One)
+            .add_system(|mut log: ResMut<Log>| log.0.push("exit One"));
+        // the transition never completes
+        nested.with_transition_update(Inner::One, Inner::Two);
+        world.insert_resource(nested);
+        world.insert_resource(NextState::<Inner>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_nested_driver_to_state::<Inner>(States::StateA);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Log(Vec::new()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<Inner>>()
+            .unwrap()
+            .set(Inner::Two);
+        stage.run(&mut world);
+        assert!(world.get_resource::<TransitionProgress<Inner>>().is_some());
+
+        // the exit of the nested state already ran when its transition started
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<Log>().unwrap().0, vec!["exit One"]);
+        assert!(world
+            .get_resource::<ScheduleStates<Inner>>()
+            .unwrap()
+            .transitioning()
+            .is_none());
+        assert!(world.get_resource::<TransitionProgress<Inner>>().is_none());
+
+        // an enter that isn't ready yet is dropped too
+        world
+            .get_resource_mut::<ScheduleStates<Inner>>()
+            .unwrap()
+            .enter_until_ready(Inner::One);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateA);
+        stage.run(&mut world);
+        assert!(world.get_resource::<EnterReady<Inner>>().is_some());
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<Log>().unwrap().0,
+            vec!["exit One", "exit One"]
+        );
+        assert!(!world
+            .get_resource::<ScheduleStates<Inner>>()
+            .unwrap()
+            .is_entering());
+        assert!(world.get_resource::<EnterReady<Inner>>().is_none());
+    }
+
+    #[derive(Debug, TypeUuid)]
+    #[uuid = "0d5d2ba8-1d3b-4c5e-9c32-7a1f1e3a7a45"]
+    struct Text;
+
+    #[derive(Default)]
+    struct TextLoader;
+
+    impl AssetLoader for TextLoader {
+        fn load<'a>(
+            &'a self,
+            _bytes: &'a [u8],
+            load_context: &'a mut LoadContext,
+        ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+            Box::pin(async move {
+                load_context.set_default_asset(LoadedAsset::new(Text));
+                Ok(())
+            })
+        }
+
+        fn extensions(&self) -> &[&str] {
+            &["txt"]
+        }
+    }
+
+    /// `AssetIo` that only has `loading/level.txt`, so loading finishes without waiting
+    /// on the file system
+    struct MemoryAssetIo;
+
+    impl AssetIo for MemoryAssetIo {
+        fn load_path<'a>(
+            &'a self,
+            path: &'a Path,
+        ) -> BoxedFuture<'a, Result<Vec<u8>, AssetIoError>> {
+            Box::pin(async move {
+                if path == Path::new("loading/level.txt") {
+                    Ok(b"level".to_vec())
+                } else {
+                    Err(AssetIoError::NotFound(path.to_path_buf()))
+                }
+            })
+        }
+
+        fn read_directory(
+            &self,
+            path: &Path,
+        ) -> Result<Box<dyn Iterator<Item = PathBuf>>, AssetIoError> {
+            Err(AssetIoError::NotFound(path.to_path_buf()))
+        }
+
+        fn is_directory(&self, _path: &Path) -> bool {
+            false
+        }
+
+        fn watch_path_for_changes(&self, _path: &Path) -> Result<(), AssetIoError> {
+            Ok(())
+        }
+
+        fn watch_for_changes(&self) -> Result<(), AssetIoError> {
+            Ok(())
+        }
+    }
+
+    #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+    enum LoadingStates {
+        Loading,
+        Loaded,
+        Failed,
+    }
+
+    /// an app that loads `Text` assets from `MemoryAssetIo` on `task_pool`
+    fn asset_app(task_pool: &TaskPool) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .insert_resource(AssetServer::new(MemoryAssetIo, task_pool.clone()))
+            .add_plugin(AssetPlugin)
+            .add_asset::<Text>()
+            .init_asset_loader::<TextLoader>();
+        app
+    }
+
+    /// loads the asset at `path` in the `Loading` state and returns the state the
+    /// app ends up in
+    fn run_loading_state(path: &str) -> LoadingStates {
+        // the load runs on the only thread of the pool and nothing in it waits, so it is
+        // complete once a task spawned after it has run
+        let task_pool = TaskPoolBuilder::new().num_threads(1).build();
+        let mut app = asset_app(&task_pool);
+        app.add_plugin(StatePlugin::new(LoadingStates::Loading))
+            .add_system(driver::<LoadingStates>.exclusive_system())
+            .add_loading_state(
+                LoadingStates::Loading,
+                LoadingStates::Loaded,
+                LoadingStates::Failed,
+            );
+
+        let handle = app
+            .world
+            .get_resource::<AssetServer>()
+            .unwrap()
+            .load_untyped(path);
+        app.add_loading_handle(LoadingStates::Loading, handle);
+        task_pool.scope(|scope| scope.spawn(async {}));
+
+        app.update();
+        app.world
+            .get_resource::<ScheduleStates<LoadingStates>>()
+            .unwrap()
+            .current_state()
+    }
+
+    #[test]
+    fn loading_state() {
+        assert!(run_loading_state("loading/level.txt") == LoadingStates::Loaded);
+        assert!(run_loading_state("loading/missing.txt") == LoadingStates::Failed);
+    }
+
+    #[test]
+    fn loading_state_with_data() {
+        // a `Copy` state can still use a key other than itself
+        #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Level {
+            Loading(u32),
+            Playing(u32),
+            Failed,
+        }
+
+        impl StateData for Level {
+            type Key = std::mem::Discriminant<Level>;
+
+            fn key(&self) -> Self::Key {
+                std::mem::discriminant(self)
+            }
+        }
+
+        // every loading level runs the loading systems of all of them, but only the
+        // one of the current level queues its target
+        let task_pool = TaskPoolBuilder::new().num_threads(1).build();
+        let mut app = asset_app(&task_pool);
+        app.add_plugin(StatePlugin::new(Level::Loading(1)))
+            .add_system(driver::<Level>.exclusive_system());
+        for level in 1..=3 {
+            app.add_loading_state(Level::Loading(level), Level::Playing(level), Level::Failed);
+        }
+        app.update();
+        assert!(
+            app.world
+                .get_resource::<ScheduleStates<Level>>()
+                .unwrap()
+                .current_state()
+                == Level::Playing(1)
+        );
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        struct Log(Vec<&'static str>);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_enter(States::StateA)
+            .add_system(|mut log: ResMut<Log>| log.0.push("enter A"));
+        states
+            .with_state_exit(States::StateB)
+            .add_system(|mut log: ResMut<Log>| log.0.push("exit B"));
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Log(Vec::new()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+
+        let snapshot = <(States,)>::snapshot(&world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateA);
+
+        // restoring doesn't run any schedules
+        <(States,)>::restore(&mut world, snapshot, false);
+        assert!(current_state(&world) == States::StateA);
+        assert!(snapshot_state::<States>(&world) == snapshot.0);
+        assert_eq!(world.get_resource::<Log>().unwrap().0, vec!["enter A"]);
+
+        // replaying the enter runs the enter of the restored state on the next run
+        restore_state(&mut world, snapshot.0, true);
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<Log>().unwrap().0,
+            vec!["enter A", "enter A"]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "missing from the snapshot group")]
+    fn snapshot_nested_machines() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Inner {
+            One,
+            Two,
+        }
+
+        let mut world = World::new();
+        world.insert_resource(ScheduleStates::new(Inner::One));
+        world.insert_resource(NextState::<Inner>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_nested_driver_to_state::<Inner>(States::StateA);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        let snapshot = <(States, Inner)>::snapshot(&world);
+        world
+            .get_resource_mut::<NextState<Inner>>()
+            .unwrap()
+            .set(Inner::Two);
+        stage.run(&mut world);
+        <(States, Inner)>::restore(&mut world, snapshot, false);
+        assert!(
+            world
+                .get_resource::<ScheduleStates<Inner>>()
+                .unwrap()
+                .current_state()
+                == Inner::One
+        );
+
+        // leaving out the nested machine would leave it in the state it is in
+        <(States,)>::snapshot(&world);
+    }
+
+    #[test]
+    fn rollback() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_timed_transition(States::StateA, States::StateB, 3);
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        states
+            .with_state_exit(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.exits += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        stage.run(&mut world);
+
+        let snapshot = snapshot_state::<States>(&world);
+        let checksum = <(States,)>::checksum(&world);
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_ne!(checksum, <(States,)>::checksum(&world));
+
+        restore_state(&mut world, snapshot, false);
+        assert_eq!(checksum, <(States,)>::checksum(&world));
+        assert_eq!(
+            snapshot.checksum(),
+            snapshot_state::<States>(&world).checksum()
+        );
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.exits, 0);
+
+        // the timed transition continues from where it was when the snapshot was taken
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.enters, 2);
+    }
+
+    #[test]
+    fn checksum_ignores_time() {
+        fn run_world(delay: Duration) -> StateSnapshot<States> {
+            let mut world = World::new();
+            let mut states = ScheduleStates::new(States::StateA);
+            states.set_fixed_timestep(States::StateA, Duration::from_secs(60));
+            world.insert_resource(states);
+            world.insert_resource(NextState::<States>::default());
+            world.insert_resource(Time::default());
+
+            let mut stage =
+                SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+            std::thread::sleep(delay);
+            world.get_resource_mut::<Time>().unwrap().update();
+            stage.run(&mut world);
+            world.get_resource_mut::<Time>().unwrap().update();
+            stage.run(&mut world);
+            snapshot_state::<States>(&world)
+        }
+
+        // the second world enters the state later after its startup
+        let first = run_world(Duration::ZERO);
+        let second = run_world(Duration::from_millis(5));
+        assert!(first.entered_at != second.entered_at);
+        assert_eq!(first.checksum(), second.checksum());
+    }
+
+    #[test]
+    fn restore_during_transition() {
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_transition_update(States::StateA, States::StateB)
+            .add_system(|mut progress: ResMut<TransitionProgress<States>>| {
+                let next = progress.progress() + 0.25;
+                progress.set_progress(next);
+            });
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        stage.run(&mut world);
+
+        let snapshot = snapshot_state::<States>(&world);
+        stage.run(&mut world);
+        restore_state(&mut world, snapshot, false);
+
+        // the progress of the transition is restored with the rest of the state
+        let progress = world.get_resource::<TransitionProgress<States>>().unwrap();
+        assert_eq!(progress.frames(), 2);
+        assert_eq!(progress.progress(), 0.5);
+        assert!(snapshot_state::<States>(&world) == snapshot);
+
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateA);
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.enters, 1);
+    }
+
+    #[test]
+    fn state_graph() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Nested {
+            Running,
+        }
+
+        let mut world = World::new();
+        let mut nested = ScheduleStates::new(Nested::Running);
+        nested.with_state_update(Nested::Running).add_system(|| {});
+        world.insert_resource(nested);
+        world.insert_resource(NextState::<Nested>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.with_state_enter(States::StateA).add_system(|| {});
+        states.with_state_update(States::StateA).add_system(|| {});
+        states.add_timed_transition(States::StateA, States::StateB, 1);
+        states.add_nested_driver_to_state::<Nested>(States::StateB);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+
+        let mut graph = StateGraph::new();
+        graph.add_machine::<States>(&world);
+        let dot = graph.to_dot();
+        assert!(dot.contains("m0_s0 [label=\"StateA\\nenter: 1, update: 1, exit: 0\"];"));
+        assert!(
+            dot.contains("m0_s1 [label=\"StateB\\nenter: 1, update: 1, exit: 1\", penwidth=2];")
+        );
+        assert!(dot.contains("m0_s0 -> m0_s1 [label=\"observed, timed\"];"));
+        assert!(dot.contains("m0_s1_n0 [label=\"Nested\", style=dashed];"));
+
+        graph.add_machine::<Nested>(&world);
+        let dot = graph.to_dot();
+        assert!(dot.contains("m0_s1 -> m1_s0 [lhead=cluster_1, style=dashed, label=\"nested\"];"));
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid
+            .contains("        state \"StateB\" as m0_s1 {\n            state \"Nested\" as m1 {"));
+        assert!(mermaid.contains("        m0_s0 --> m0_s1 : observed, timed"));
+        assert!(mermaid.contains("    class m0_s1 current"));
+    }
+
+    #[test]
+    fn introspection() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        struct Nested;
+
+        fn count_enters(mut counts: ResMut<StateACounts>) {
+            counts.0.enters += 1;
+        }
+
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_enter(States::StateA)
+            .add_system(count_enters);
+        states.add_nested_driver_to_state::<Nested>(States::StateB);
+
+        let mut registered = states.states();
+        registered.sort_by_key(|state| *state as usize);
+        assert!(registered == vec![States::StateA, States::StateB]);
+
+        let names = states.enter_system_names(States::StateA);
+        assert_eq!(names.len(), 1);
+        assert!(names[0].ends_with("count_enters"));
+        assert!(states.update_system_names(States::StateA).is_empty());
+        assert!(states.update_system_names(States::StateB)[0].contains("driver"));
+
+        assert!(!states.has_nested_drivers(States::StateA));
+        let nested = states.nested_drivers(States::StateB);
+        assert_eq!(nested.len(), 1);
+        assert_eq!(nested[0].type_id(), std::any::TypeId::of::<Nested>());
+    }
+
+    #[test]
+    fn state_diagnostics() {
+        use bevy::diagnostic::{Diagnostics, DiagnosticsPlugin};
+
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_plugin(DiagnosticsPlugin)
+            .add_plugin(StatePlugin::new(States::StateA))
+            .add_plugin(StateDiagnosticsPlugin::<States>::default())
+            .add_system(driver::<States>.exclusive_system())
+            .add_transition_system_to_update(States::StateA, || Some(States::StateB))
+            .add_system_to_update(States::StateB, || {});
+        app.update();
+
+        let diagnostics = app.world.get_resource::<Diagnostics>().unwrap();
+        // state a updates and queues state b, then state b updates in the same call
+        let driver_loops = StateDiagnosticsPlugin::<States>::driver_loops();
+        assert_eq!(
+            diagnostics.get_measurement(driver_loops).unwrap().value,
+            2.0
+        );
+        for state in [States::StateA, States::StateB] {
+            let update_time = StateDiagnosticsPlugin::<States>::update_time(state);
+            assert!(diagnostics.get_measurement(update_time).is_some());
+        }
+        let transitions = StateDiagnosticsPlugin::<States>::transitions_per_second();
+        assert!(diagnostics.get(transitions).is_some());
+    }
+
+    #[test]
+    fn split_driver() {
+        struct Log(Vec<&'static str>);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_update(States::StateA)
+            .add_system((|| Some(States::StateB)).chain(set_next_state::<States>));
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut log: ResMut<Log>| log.0.push("update A"));
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut log: ResMut<Log>| log.0.push("enter B"));
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut log: ResMut<Log>| log.0.push("update B"));
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Log(Vec::new()));
+
+        let mut schedule = Schedule::default();
+        schedule.add_stage(
+            CoreStage::PreUpdate,
+            SystemStage::parallel().with_system(apply_transitions::<States>.exclusive_system()),
+        );
+        schedule.add_stage(
+            CoreStage::Update,
+            SystemStage::parallel().with_system(run_state_update::<States>.exclusive_system()),
+        );
+        schedule.run(&mut world);
+        // state b was queued by the update, but is applied in the next pre update
+        assert!(current_state(&world) == States::StateA);
+        assert_eq!(world.get_resource::<Log>().unwrap().0, vec!["update A"]);
+
+        schedule.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(
+            world.get_resource::<Log>().unwrap().0,
+            vec!["update A", "enter B", "update B"]
+        );
+    }
+
+    #[test]
+    fn state_machine_stage() {
+        struct Paused(bool);
+
+        let mut world = World::new();
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateACounts(Counts::default()));
+        world.insert_resource(Paused(true));
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_enter(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.updates += 1);
+        let mut stage = StateMachineStage::new(states).with_run_criteria(|paused: Res<Paused>| {
+            if paused.0 {
+                ShouldRun::No
+            } else {
+                ShouldRun::Yes
+            }
+        });
+
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0,
+            Counts::default()
+        );
+
+        world.get_resource_mut::<Paused>().unwrap().0 = false;
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0,
+            Counts {
+                enters: 1,
+                updates: 1,
+                exits: 0
+            }
+        );
+
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        assert!(stage.states().current_state() == States::StateB);
+    }
+
+    #[test]
+    fn parallel_states_stage() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Mode {
+            On,
+        }
+        struct Arrived(AtomicU32);
+        struct Together(AtomicU32);
+        struct Log(Vec<&'static str>);
+
+        // only returns in time if the update system of the other machine runs at the
+        // same time
+        fn meet(arrived: Res<Arrived>, together: Res<Together>) {
+            arrived.0.fetch_add(1, Ordering::SeqCst);
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5) {
+                if arrived.0.load(Ordering::SeqCst) >= 2 {
+                    together.0.fetch_add(1, Ordering::SeqCst);
+                    return;
+                }
+                std::hint::spin_loop();
+            }
+        }
+
+        let mut world = World::new();
+        world.insert_resource(ComputeTaskPool(
+            TaskPoolBuilder::new().num_threads(2).build(),
+        ));
+        let mut states = ScheduleStates::new(States::StateA);
+        states
+            .with_state_update(States::StateA)
+            .add_system(meet)
+            .add_system(
+                |mut log: ResMut<Log>, mut next_state: ResMut<NextState<States>>| {
+                    log.0.push("state a");
+                    next_state.set(States::StateB);
+                },
+            );
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        let mut mode = ScheduleStates::new(Mode::On);
+        mode.with_state_update(Mode::On)
+            .add_system(meet)
+            .add_system(|mut log: ResMut<Log>| log.0.push("mode on"));
+        world.insert_resource(mode);
+        world.insert_resource(NextState::<Mode>::default());
+        world.insert_resource(Arrived(AtomicU32::new(0)));
+        world.insert_resource(Together(AtomicU32::new(0)));
+        world.insert_resource(Log(Vec::new()));
+
+        let mut stage = ParallelStatesStage::new()
+            .with_machine::<States>()
+            .with_machine::<Mode>();
+        stage.run(&mut world);
+        // the update systems of both machines ran in the same batch
+        assert_eq!(
+            world
+                .get_resource::<Together>()
+                .unwrap()
+                .0
+                .load(Ordering::SeqCst),
+            2
+        );
+        // the systems that both write to the log ran one after another
+        let mut log = world.get_resource::<Log>().unwrap().0.clone();
+        log.sort_unstable();
+        assert_eq!(log, vec!["mode on", "state a"]);
+
+        stage.run(&mut world);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(world.get_resource::<Log>().unwrap().0.len(), 3);
+        assert_eq!(world.get_resource::<Log>().unwrap().0[2], "mode on");
+    }
+
+    #[test]
+    fn indexed_storage() {
+        #[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Level {
+            Start,
+            End,
+        }
+
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::new_indexed(Level::Start))
+            .insert_resource(StateBCounts(Counts::default()))
+            .add_system(driver::<Level>.exclusive_system())
+            .add_transition_system_to_update(Level::Start, || Some(Level::End))
+            .add_system_to_enter(Level::End, |mut counts: ResMut<StateBCounts>| {
+                counts.0.enters += 1
+            });
+        app.update();
+
+        let states = app.world.get_resource::<ScheduleStates<Level>>().unwrap();
+        assert!(states.is_indexed());
+        assert!(states.current_state() == Level::End);
+        let mut registered = states.states();
+        registered.sort_by_key(|state| *state as usize);
+        assert!(registered == vec![Level::Start, Level::End]);
+        assert_eq!(
+            app.world.get_resource::<StateBCounts>().unwrap().0.enters,
+            1
+        );
+
+        // `ScheduleState`s pick the indexed storage by default
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::<Level>::default());
+        let states = app.world.get_resource::<ScheduleStates<Level>>().unwrap();
+        assert!(states.is_indexed());
+        assert!(!ScheduleStates::new(Level::Start).is_indexed());
+    }
+
+    #[test]
+    fn lazy_schedules() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Lazy {
+            Start,
+            Empty,
+            End,
+        }
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(Lazy::Start);
+        states
+            .with_state_update(Lazy::Start)
+            .add_system((|| Some(Lazy::Empty)).chain(set_next_state::<Lazy>));
+        // `Empty` has no schedules and the enter schedule of `End` has no systems
+        states.add_timed_transition(Lazy::Empty, Lazy::End, 1);
+        states.with_state_enter(Lazy::End);
+
+        let mut registered = states.states();
+        registered.sort_by_key(|state| *state as usize);
+        assert!(registered == vec![Lazy::Start, Lazy::End]);
+        assert!(states.enter_system_names(Lazy::Start).is_empty());
+
+        world.insert_resource(states);
+        world.insert_resource(NextState::<Lazy>::default());
+        let mut stage = SystemStage::parallel().with_system(driver::<Lazy>.exclusive_system());
+        stage.run(&mut world);
+        assert!(
+            world
+                .get_resource::<ScheduleStates<Lazy>>()
+                .unwrap()
+                .current_state()
+                == Lazy::End
+        );
+    }
+
+    #[test]
+    fn derive_schedule_state() {
+        // the derive only implements `ScheduleState` and `StateIndex`, so the std traits
+        // don't conflict
+        #[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Menu {
+            Title,
+            #[initial]
+            Options,
+        }
+
+        assert!(Menu::VARIANTS == [Menu::Title, Menu::Options]);
+        assert_eq!(Menu::Options.name(), "Options");
+        assert_eq!(Menu::Options.index(), 1);
+        assert_eq!(Menu::COUNT, 2);
+
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::<Menu>::default());
+        let states = app.world.get_resource::<ScheduleStates<Menu>>().unwrap();
+        assert!(states.current_state() == Menu::Options);
+    }
+
+    #[test]
+    fn validation() {
+        #[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Screen {
+            Title,
+            Game,
+        }
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        struct Orphan;
+
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::new(Screen::Title))
+            .add_system_to_update(Screen::Title, || {});
+        let world = &mut app.world;
+        assert!(
+            validate_states::<Screen>(world)
+                == vec![StateValidationError::NoSystems {
+                    state_type: std::any::type_name::<Screen>(),
+                    state: "Game",
+                }]
+        );
+
+        world
+            .get_resource_mut::<ScheduleStates<Screen>>()
+            .unwrap()
+            .add_nested_driver_to_state::<Orphan>(Screen::Game);
+        let errors = validate_states::<Screen>(world);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            StateValidationError::MissingNestedStates { state: "Game", .. }
+        ));
+
+        world.insert_resource(ScheduleStates::new(Orphan));
+        assert!(validate_states::<Screen>(world).is_empty());
+
+        // states without systems of their own run the any state systems
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::new(Screen::Title))
+            .add_system_to_any_update::<Screen, _>(|| {});
+        assert!(validate_states::<Screen>(&app.world).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "has no enter, update or exit systems")]
+    fn validation_plugin() {
+        #[derive(ScheduleState, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Screen {
+            Title,
+            Game,
+        }
+
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::<Screen>::default())
+            .add_plugin(StateValidationPlugin::<Screen>::new(
+                ValidationFailure::Panic,
+            ))
+            .add_system_to_update(Screen::Title, || {});
+        app.update();
+    }
+
+    #[test]
+    fn update_budget() {
+        struct GuardChecks(u32);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.set_update_budget(3, CatchUpPolicy::Carry);
+        // the states keep switching to each other, so without a budget the driver
+        // would never return
+        states
+            .with_state_update(States::StateA)
+            .add_system((|| Some(States::StateB)).chain(set_next_state::<States>));
+        states
+            .with_state_update(States::StateB)
+            .add_system((|| Some(States::StateA)).chain(set_next_state::<States>));
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.updates += 1);
+        states
+            .with_state_enter(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.enters += 1);
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        states.add_transition_guard(
+            States::StateA,
+            States::StateB,
+            |mut checks: ResMut<GuardChecks>| {
+                checks.0 += 1;
+                true
+            },
+        );
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Events::<UpdateBudgetExceeded<States>>::default());
+        world.insert_resource(GuardChecks(0));
+        world.insert_resource(StateACounts(Counts::default()));
+        world.insert_resource(StateBCounts(Counts::default()));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<StateACounts>().unwrap().0.updates, 2);
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.updates, 1);
+        assert!(current_state(&world) == States::StateA);
+
+        let events = world
+            .get_resource::<Events<UpdateBudgetExceeded<States>>>()
+            .unwrap();
+        let exceeded: Vec<_> = events.get_reader().iter(events).copied().collect();
+        assert_eq!(exceeded.len(), 1);
+        assert!(exceeded[0].next_state == Some(States::StateB));
+        assert_eq!(world.get_resource::<GuardChecks>().unwrap().0, 2);
+
+        // the transition that was left is applied on the next call without running its
+        // guard again
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.enters, 3);
+        assert!(current_state(&world) == States::StateB);
+        assert_eq!(world.get_resource::<GuardChecks>().unwrap().0, 3);
+    }
+
+    #[test]
+    fn fixed_timestep() {
+        let step = Duration::from_micros(1);
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.set_fixed_timestep(States::StateA, step);
+        states
+            .with_state_update(States::StateA)
+            .add_system(|mut counts: ResMut<StateACounts>| counts.0.updates += 1);
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(StateACounts(Counts::default()));
+        world.insert_resource(StateBCounts(Counts::default()));
+        world.insert_resource(Time::default());
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        world.get_resource_mut::<Time>().unwrap().update();
+        stage.run(&mut world);
+        // entering the state resets its fixed time
+        assert_eq!(world.get_resource::<StateACounts>().unwrap().0.updates, 0);
+        let alpha = world.get_resource::<InterpolationAlpha<States>>().unwrap();
+        assert!(alpha.state() == States::StateA);
+        assert_eq!(alpha.alpha(), 0.0);
+
+        // `Time` can't be advanced by a chosen duration in bevy 0.6, so the expected
+        // updates are worked out from the delta it measured
+        world.get_resource_mut::<Time>().unwrap().update();
+        let delta = world.get_resource::<Time>().unwrap().delta();
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0.updates as u128,
+            delta.as_nanos() / step.as_nanos()
+        );
+        let left_over = (delta.as_nanos() % step.as_nanos()) as f32 / step.as_nanos() as f32;
+        let alpha = world.get_resource::<InterpolationAlpha<States>>().unwrap();
+        assert!((alpha.alpha() - left_over).abs() < 1e-3);
+
+        // running the driver again in the same frame doesn't add the frame time again
+        stage.run(&mut world);
+        assert_eq!(
+            world.get_resource::<StateACounts>().unwrap().0.updates as u128,
+            delta.as_nanos() / step.as_nanos()
+        );
+
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<StateBCounts>().unwrap().0.updates, 1);
+        assert!(world.get_resource::<InterpolationAlpha<States>>().is_none());
+    }
+
+    #[test]
+    fn data_carrying_states() {
+        #[derive(PartialEq, Eq, Hash, Clone, Debug)]
+        enum GameState {
+            Menu,
+            Level(String),
+        }
+
+        #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum GameStateKey {
+            Menu,
+            Level,
+        }
+
+        impl StateData for GameState {
+            type Key = GameStateKey;
+
+            fn key(&self) -> GameStateKey {
+                match self {
+                    GameState::Menu => GameStateKey::Menu,
+                    GameState::Level(_) => GameStateKey::Level,
+                }
+            }
+        }
+
+        struct Seen(Vec<Option<GameState>>);
+
+        fn record(current: Option<Res<CurrentState<GameState>>>, mut seen: ResMut<Seen>) {
+            seen.0.push(current.map(|current| current.get().clone()));
+        }
+
+        let mut world = World::new();
+        world.insert_resource(NextState::<GameState>::default());
+        world.insert_resource(Seen(Vec::new()));
+        let mut states = ScheduleStates::new(GameState::Menu);
+        states
+            .with_state_enter(GameStateKey::Level)
+            .add_system(record);
+        // the exited level has been removed by the time the transition runs
+        states
+            .with_transition_update(GameStateKey::Level, GameStateKey::Menu)
+            .add_system(
+                record
+                    .chain(|mut progress: ResMut<TransitionProgress<GameState>>| progress.finish()),
+            );
+        world.insert_resource(states);
+
+        let mut stage = SystemStage::parallel().with_system(driver::<GameState>.exclusive_system());
+        stage.run(&mut world);
+        for next in [
+            GameState::Level("first".to_string()),
+            GameState::Level("second".to_string()),
+            GameState::Menu,
+        ] {
+            world
+                .get_resource_mut::<NextState<GameState>>()
+                .unwrap()
+                .set(next);
+            stage.run(&mut world);
+        }
+
+        // both levels share the enter `Schedule`, which reads the full state
+        assert_eq!(
+            world.get_resource::<Seen>().unwrap().0,
+            vec![
+                Some(GameState::Level("first".to_string())),
+                Some(GameState::Level("second".to_string())),
+                None,
+            ]
+        );
+        let states = world.get_resource::<ScheduleStates<GameState>>().unwrap();
+        assert!(states.is_in(&GameStateKey::Menu));
+        assert!(world.get_resource::<CurrentState<GameState>>().is_some());
+
+        // states that don't carry data don't get a `CurrentState`
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::new(States::StateA))
+            .add_system(driver::<States>.exclusive_system());
+        app.update();
+        assert!(app.world.get_resource::<CurrentState<States>>().is_none());
+    }
+
+    #[test]
+    fn transition_payload() {
+        struct Seed(Option<u64>);
+
+        let mut world = World::new();
+        let mut states = ScheduleStates::new(States::StateA);
+        states.with_state_enter(States::StateB).add_system(
+            |context: Option<Res<TransitionContext<States, u64>>>, mut seed: ResMut<Seed>| {
+                seed.0 = context.map(|context| *context.payload());
+            },
+        );
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(Seed(None));
+
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set_with(States::StateB, 42u64);
+        stage.run(&mut world);
+        assert_eq!(world.get_resource::<Seed>().unwrap().0, Some(42));
+        // the context only exists while the state is entered
+        assert!(world
+            .get_resource::<TransitionContext<States, u64>>()
+            .is_none());
+
+        // states queued with `set` have no payload
+        for state in [States::StateA, States::StateB] {
+            world
+                .get_resource_mut::<NextState<States>>()
+                .unwrap()
+                .set(state);
+            stage.run(&mut world);
+        }
+        assert_eq!(world.get_resource::<Seed>().unwrap().0, None);
+    }
+
+    #[test]
+    fn any_state_systems() {
+        struct Log(Vec<&'static str>);
+
+        let mut app = App::new();
+        app.add_plugin(StatePlugin::new(States::StateA))
+            .insert_resource(Log(Vec::new()))
+            .add_system(driver::<States>.exclusive_system())
+            .add_system_to_any_enter::<States, _>(|mut log: ResMut<Log>| log.0.push("any enter"))
+            .add_system_to_any_update::<States, _>(|mut log: ResMut<Log>| log.0.push("any update"))
+            .add_system_to_any_exit::<States, _>(|mut log: ResMut<Log>| log.0.push("any exit"))
+            .add_system_to_enter(States::StateA, |mut log: ResMut<Log>| log.0.push("enter A"))
+            .add_system_to_exit(States::StateA, |mut log: ResMut<Log>| log.0.push("exit A"))
+            .add_transition_system_to_update(States::StateA, || Some(States::StateB));
+        app.update();
+
+        // states without systems of their own still run the any state systems
+        assert_eq!(
+            app.world.get_resource::<Log>().unwrap().0,
+            vec![
+                "any enter",
+                "enter A",
+                "any update",
+                "exit A",
+                "any exit",
+                "any enter",
+                "any update"
+            ]
+        );
+    }
+
+    #[test]
+    fn any_state_repeated_runs() {
+        #[derive(StateData, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+        enum Nested {
+            Loading,
+        }
+        struct AnyEnters(u32);
+        struct AnyUpdates(u32);
+
+        let step = Duration::from_micros(1);
+        let mut world = World::new();
+        let mut nested = ScheduleStates::new(Nested::Loading);
+        nested.enter_until_ready(Nested::Loading);
+        nested.with_state_enter(Nested::Loading).add_system(
+            (|ready: Res<EnterReady<Nested>>| ready.frames() >= 2).chain(set_enter_ready::<Nested>),
+        );
+        nested
+            .with_any_state_enter()
+            .add_system(|mut enters: ResMut<AnyEnters>| enters.0 += 1);
+        world.insert_resource(nested);
+        world.insert_resource(NextState::<Nested>::default());
+        let mut states = ScheduleStates::new(States::StateA);
+        states.add_nested_driver_to_state::<Nested>(States::StateA);
+        states.set_fixed_timestep(States::StateB, step);
+        states
+            .with_any_state_update()
+            .add_system(|mut updates: ResMut<AnyUpdates>| updates.0 += 1);
+        states
+            .with_state_update(States::StateB)
+            .add_system(|mut counts: ResMut<StateBCounts>| counts.0.updates += 1);
+        world.insert_resource(states);
+        world.insert_resource(NextState::<States>::default());
+        world.insert_resource(AnyEnters(0));
+        world.insert_resource(AnyUpdates(0));
+        world.insert_resource(StateBCounts(Counts::default()));
+        world.insert_resource(Time::default());
+
+        // the nested state is entered by the state it is nested in and by its own driver,
+        // and is ready on the next run
+        let mut stage = SystemStage::parallel().with_system(driver::<States>.exclusive_system());
+        stage.run(&mut world);
+        stage.run(&mut world);
+        assert!(world.get_resource::<EnterReady<Nested>>().is_none());
+        assert_eq!(world.get_resource::<AnyEnters>().unwrap().0, 1);
+
+        world
+            .get_resource_mut::<NextState<States>>()
+            .unwrap()
+            .set(States::StateB);
+        world.get_resource_mut::<Time>().unwrap().update();
+        stage.run(&mut world);
+        let any_updates = world.get_resource::<AnyUpdates>().unwrap().0;
+
+        // the any state update runs with every step of the fixed timestep
+        world.get_resource_mut::<Time>().unwrap().update();
+        let delta = world.get_resource::<Time>().unwrap().delta();
+        stage.run(&mut world);
+        let updates = world.get_resource::<StateBCounts>().unwrap().0.updates;
+        assert_eq!(updates as u128, delta.as_nanos() / step.as_nanos());
+        assert_eq!(
+            world.get_resource::<AnyUpdates>().unwrap().0 - any_updates,
+            updates
+        );
+    }
 }
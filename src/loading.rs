@@ -0,0 +1,83 @@
+use bevy::{
+    asset::{AssetServer, HandleUntyped, LoadState},
+    prelude::{Res, ResMut},
+    utils::HashMap,
+};
+
+use crate::{CurrentState, NextState, StateData};
+
+/// Resource of the asset handles that loading states wait on. The handles are stored
+/// under the key of the state, so states that carry data share them.
+pub struct LoadingHandles<S>
+where
+    S: StateData,
+{
+    handles: HashMap<S::Key, Vec<HandleUntyped>>,
+}
+
+impl<S> Default for LoadingHandles<S>
+where
+    S: StateData,
+{
+    fn default() -> Self {
+        LoadingHandles {
+            handles: HashMap::default(),
+        }
+    }
+}
+
+impl<S> LoadingHandles<S>
+where
+    S: StateData,
+{
+    /// add a handle that loading `state` waits on. The handle is kept alive
+    /// until it is cleared.
+    pub fn add(&mut self, state: S::Key, handle: HandleUntyped) {
+        self.handles.entry(state).or_default().push(handle);
+    }
+
+    /// get the handles that loading `state` waits on
+    pub fn handles(&self, state: &S::Key) -> &[HandleUntyped] {
+        self.handles.get(state).map_or(&[], |handles| handles)
+    }
+
+    /// remove all the handles of loading `state`
+    pub fn clear(&mut self, state: &S::Key) {
+        self.handles.remove(state);
+    }
+}
+
+/// creates a system for the update `Schedule` of `loading` that queues `loaded`
+/// once all the handles of `loading` are loaded and `failed` if any of them failed
+/// to load. For states that carry data the system only does this while the
+/// `CurrentState<S>` is `loading`, since all the states with its key share the
+/// update `Schedule`.
+#[allow(clippy::type_complexity)]
+pub fn loading_state_system<S>(
+    loading: S,
+    loaded: S,
+    failed: S,
+) -> impl FnMut(
+    Res<AssetServer>,
+    Res<LoadingHandles<S>>,
+    Option<Res<CurrentState<S>>>,
+    ResMut<NextState<S>>,
+)
+where
+    S: StateData,
+{
+    move |asset_server, handles, current_state, mut next_state| {
+        if current_state.map_or(false, |current_state| *current_state.get() != loading) {
+            return;
+        }
+        let handles = handles
+            .handles(&loading.key())
+            .iter()
+            .map(|handle| handle.id);
+        match asset_server.get_group_load_state(handles) {
+            LoadState::Loaded => next_state.set(loaded.clone()),
+            LoadState::Failed => next_state.set(failed.clone()),
+            _ => {}
+        }
+    }
+}
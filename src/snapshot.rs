@@ -0,0 +1,241 @@
+use bevy::{
+    prelude::{Mut, World},
+    utils::Duration,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    any::TypeId,
+    hash::{Hash, Hasher},
+};
+
+use crate::{ScheduleStates, StateData};
+
+/// A copy of the state of a `ScheduleStates<S>` and its `NextState<S>`, without the
+/// payload of the queued state
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StateSnapshot<S> {
+    /// the state the machine is in
+    pub current_state: S,
+    /// true if the state has not been entered yet
+    pub first_run: bool,
+    /// the state queued in `NextState<S>`
+    pub next_state: Option<S>,
+    /// true if `next_state` was requeued after its guards passed, so they don't run again
+    pub guards_passed: bool,
+    /// the `(from, to)` states of the multi frame transition in progress
+    pub transitioning: Option<(S, S)>,
+    /// the frames of the `TransitionProgress<S>` of the transition in progress and the
+    /// bits of its progress, so the snapshot can be hashed
+    pub transition_progress: Option<(u32, u32)>,
+    /// true while the enter `Schedule` of the current state runs until ready
+    pub entering: bool,
+    /// the frames of the `EnterReady<S>` of the enter in progress and whether it is ready
+    pub enter_ready: Option<(u32, bool)>,
+    /// number of times the update `Schedule` ran since the current state was entered
+    pub ticks_in_state: u32,
+    /// `Time::time_since_startup` when the current state was entered
+    pub entered_at: Option<Duration>,
+    /// time accumulated for the fixed timestep of the current state that hasn't been
+    /// used up by its updates yet
+    pub fixed_time: Duration,
+}
+
+impl<S: Hash> StateSnapshot<S> {
+    /// a hash of the snapshot that is the same on every run of the app and on every
+    /// platform, as long as the `Hash` implementation of `S` is. Comparing checksums
+    /// is a cheap way to detect that two peers are in different states.
+    ///
+    /// `entered_at` and `fixed_time` come from the wall clock, so they are left out.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        self.current_state.hash(&mut hasher);
+        self.first_run.hash(&mut hasher);
+        self.next_state.hash(&mut hasher);
+        self.guards_passed.hash(&mut hasher);
+        self.transitioning.hash(&mut hasher);
+        self.transition_progress.hash(&mut hasher);
+        self.entering.hash(&mut hasher);
+        self.enter_ready.hash(&mut hasher);
+        self.ticks_in_state.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// FNV-1a hasher that writes integers as little endian 64 bit values, so the hash
+/// doesn't depend on the platform or on the random keys of the std hasher
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_u64(i.into());
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write_u64(i.into());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write_u64(i.into());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i8(&mut self, i: i8) {
+        self.write_i64(i.into());
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_i64(i.into());
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_i64(i.into());
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+/// A group of state machines that can be snapshotted and restored together. This is
+/// implemented for tuples of state types.
+///
+/// Only the machines in the tuple are included, so the machines nested in them have to
+/// be listed too, e.g. `<(Game, Menu)>::snapshot` for a `Menu` machine nested in a `Game`
+/// state. The methods panic if a nested machine is missing from the tuple.
+pub trait SnapshotStates {
+    /// the snapshots of all the state machines in the group
+    type Snapshot;
+
+    /// take a snapshot of all the state machines in the group
+    fn snapshot(world: &World) -> Self::Snapshot;
+
+    /// restore all the state machines in the group. If `replay_enter` is true the
+    /// enter `Schedule`s of the restored states run on the next run of their drivers.
+    fn restore(world: &mut World, snapshot: Self::Snapshot, replay_enter: bool);
+
+    /// a checksum of all the state machines in the group. See `StateSnapshot::checksum`.
+    fn checksum(world: &World) -> u64;
+}
+
+/// take a snapshot of a single state machine
+pub fn snapshot_state<S>(world: &World) -> StateSnapshot<S>
+where
+    S: StateData,
+{
+    world
+        .get_resource::<ScheduleStates<S>>()
+        .unwrap()
+        .snapshot(world)
+}
+
+/// restore a single state machine from a snapshot taken with `snapshot_state`. No
+/// schedules are run. See `ScheduleStates::restore`.
+pub fn restore_state<S>(world: &mut World, snapshot: StateSnapshot<S>, replay_enter: bool)
+where
+    S: StateData,
+{
+    world.resource_scope(|world, mut states: Mut<ScheduleStates<S>>| {
+        states.restore(world, snapshot, replay_enter);
+    });
+}
+
+/// The machines of a `SnapshotStates` tuple
+trait SnapshotGroup {
+    /// panics if a machine nested in one of the machines is not in the group
+    fn assert_complete(world: &World);
+}
+
+/// panics if a machine nested in a state of `S` is not one of the machines in `group`
+fn assert_nested_in_group<S>(world: &World, group: &[TypeId])
+where
+    S: StateData,
+{
+    let states = world.get_resource::<ScheduleStates<S>>().unwrap();
+    for nested in states.all_nested_drivers() {
+        assert!(
+            group.contains(&nested.type_id()),
+            "{} is nested in {}, but missing from the snapshot group",
+            nested.type_name(),
+            std::any::type_name::<S>()
+        );
+    }
+}
+
+macro_rules! impl_snapshot_states {
+    ($($state: ident),*) => {
+        impl<$($state),*> SnapshotStates for ($($state,)*)
+        where
+            $($state: StateData),*
+        {
+            type Snapshot = ($(StateSnapshot<$state>,)*);
+
+            fn snapshot(world: &World) -> Self::Snapshot {
+                Self::assert_complete(world);
+                ($(snapshot_state::<$state>(world),)*)
+            }
+
+            #[allow(non_snake_case)]
+            fn restore(world: &mut World, snapshot: Self::Snapshot, replay_enter: bool) {
+                Self::assert_complete(world);
+                let ($($state,)*) = snapshot;
+                $(restore_state(world, $state, replay_enter);)*
+            }
+
+            fn checksum(world: &World) -> u64 {
+                Self::assert_complete(world);
+                let mut hasher = StableHasher::default();
+                $(hasher.write_u64(snapshot_state::<$state>(world).checksum());)*
+                hasher.finish()
+            }
+        }
+
+        impl<$($state),*> SnapshotGroup for ($($state,)*)
+        where
+            $($state: StateData),*
+        {
+            fn assert_complete(world: &World) {
+                let group = [$(TypeId::of::<$state>()),*];
+                $(assert_nested_in_group::<$state>(world, &group);)*
+            }
+        }
+    };
+}
+
+impl_snapshot_states!(S0);
+impl_snapshot_states!(S0, S1);
+impl_snapshot_states!(S0, S1, S2);
+impl_snapshot_states!(S0, S1, S2, S3);
+impl_snapshot_states!(S0, S1, S2, S3, S4);
+impl_snapshot_states!(S0, S1, S2, S3, S4, S5);
+impl_snapshot_states!(S0, S1, S2, S3, S4, S5, S6);
+impl_snapshot_states!(S0, S1, S2, S3, S4, S5, S6, S7);
@@ -0,0 +1,347 @@
+use bevy::{
+    ecs::{
+        query::Access,
+        schedule::{ParallelSystemContainer, ParallelSystemExecutor, ShouldRun},
+    },
+    prelude::{IntoSystem, Stage, World},
+    tasks::{ComputeTaskPool, TaskPool},
+};
+
+use crate::{
+    apply_transitions, run_state_update, state_schedule::ManualSystem, NextState, ScheduleStates,
+    StateData,
+};
+
+/// A `Stage` that runs a state machine like `driver` does, but owns its `ScheduleStates`
+/// instead of taking it out of the `World` every run. Add it with `App::add_stage_after`
+/// to give the machine its own label and run criteria. The `NextState<S>` resource is
+/// added on the first run if it doesn't exist.
+///
+/// The `ScheduleStates<S>` isn't a resource, so it needs to be configured before the stage
+/// is created or through `states_mut`. `AppStateHelpers`, snapshots and `StateGraph` only
+/// work with machines that are resources, and the machine can't be nested in another one.
+pub struct StateMachineStage<S>
+where
+    S: StateData,
+{
+    states: ScheduleStates<S>,
+    run_criteria: Option<ManualSystem<ShouldRun>>,
+}
+
+impl<S> StateMachineStage<S>
+where
+    S: StateData,
+{
+    pub fn new(states: ScheduleStates<S>) -> Self {
+        StateMachineStage {
+            states,
+            run_criteria: None,
+        }
+    }
+
+    /// only run the machine when `run_criteria` says so. `ShouldRun::YesAndCheckAgain`
+    /// runs it again in the same frame.
+    pub fn with_run_criteria<Params>(
+        mut self,
+        run_criteria: impl IntoSystem<(), ShouldRun, Params>,
+    ) -> Self {
+        self.run_criteria = Some(ManualSystem::new(run_criteria));
+        self
+    }
+
+    /// the state machine the stage runs
+    pub fn states(&self) -> &ScheduleStates<S> {
+        &self.states
+    }
+
+    /// the state machine the stage runs. This is used for adding systems to its schedules.
+    pub fn states_mut(&mut self) -> &mut ScheduleStates<S> {
+        &mut self.states
+    }
+
+    fn should_run(&mut self, world: &mut World) -> ShouldRun {
+        match self.run_criteria.as_mut() {
+            Some(run_criteria) => run_criteria.run(world),
+            None => ShouldRun::Yes,
+        }
+    }
+}
+
+impl<S> Stage for StateMachineStage<S>
+where
+    S: StateData,
+{
+    fn run(&mut self, world: &mut World) {
+        #[cfg(feature = "trace")]
+        let span = bevy::utils::tracing::info_span!(
+            "state machine stage",
+            state_type = std::any::type_name::<S>()
+        );
+        #[cfg(feature = "trace")]
+        let _guard = span.enter();
+
+        if !world.contains_resource::<NextState<S>>() {
+            world.insert_resource(NextState::<S>::default());
+        }
+
+        // the same as how bevy's `Schedule` handles its run criteria
+        loop {
+            match self.should_run(world) {
+                ShouldRun::No => return,
+                ShouldRun::Yes => {
+                    self.states.drive(world);
+                    return;
+                }
+                ShouldRun::YesAndCheckAgain => self.states.drive(world),
+                ShouldRun::NoAndCheckAgain => {
+                    panic!("`NoAndCheckAgain` would loop infinitely in this situation.")
+                }
+            }
+        }
+    }
+}
+
+/// A `Stage` for state machines that don't depend on each other. It applies the
+/// transitions of every machine and then runs the update `Schedule`s of their current
+/// states with all of their parallel systems in one batch, so the systems of different
+/// machines run in parallel when their accesses don't conflict. Systems whose accesses
+/// conflict run one after another.
+///
+/// The `ScheduleStates` of the machines need to be resources. The any state update
+/// `Schedule`s and exclusive systems of the machines run one machine after another, and
+/// the extra updates of fixed timesteps run outside of the batch.
+pub struct ParallelStatesStage {
+    machines: Vec<IndependentMachine>,
+}
+
+/// the exclusive systems that run a machine in a `ParallelStatesStage`
+struct IndependentMachine {
+    apply_transitions: fn(&mut World),
+    batch_updates: fn(&mut World),
+    run_update: fn(&mut World),
+}
+
+impl ParallelStatesStage {
+    pub fn new() -> Self {
+        ParallelStatesStage {
+            machines: Vec::new(),
+        }
+    }
+
+    /// add the `ScheduleStates<S>` resource to the stage
+    pub fn add_machine<S>(&mut self) -> &mut Self
+    where
+        S: StateData,
+    {
+        self.machines.push(IndependentMachine {
+            apply_transitions: apply_transitions::<S>,
+            batch_updates: |world| {
+                world
+                    .get_resource_mut::<ScheduleStates<S>>()
+                    .unwrap()
+                    .batch_updates();
+            },
+            run_update: run_state_update::<S>,
+        });
+        self
+    }
+
+    /// builder version of `add_machine`
+    pub fn with_machine<S>(mut self) -> Self
+    where
+        S: StateData,
+    {
+        self.add_machine::<S>();
+        self
+    }
+}
+
+impl Default for ParallelStatesStage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stage for ParallelStatesStage {
+    fn run(&mut self, world: &mut World) {
+        for machine in self.machines.iter() {
+            (machine.apply_transitions)(world);
+            (machine.batch_updates)(world);
+        }
+        if self.machines.is_empty() {
+            return;
+        }
+        world.insert_resource(UpdateBatch {
+            run_updates: self
+                .machines
+                .iter()
+                .map(|machine| machine.run_update)
+                .collect(),
+            next: None,
+            collected: Vec::new(),
+        });
+        run_updates_from(world, 0);
+        world.remove_resource::<UpdateBatch>();
+    }
+}
+
+/// Resource that exists while a `ParallelStatesStage` runs the updates of its machines.
+/// The executor of each update `Schedule` collects its systems and starts the update of
+/// the next machine instead of running them, and the last one runs all of them at once.
+struct UpdateBatch {
+    run_updates: Vec<fn(&mut World)>,
+    /// the machine whose update the next executor starts
+    next: Option<usize>,
+    collected: Vec<CollectedSystems>,
+}
+
+/// the parallel systems of an update `Schedule` whose executor is waiting for the batch
+struct CollectedSystems(*mut [ParallelSystemContainer]);
+
+// SAFETY: the systems are only used by the thread running the stage, while the executor
+// they belong to waits for the batch to finish
+unsafe impl Send for CollectedSystems {}
+unsafe impl Sync for CollectedSystems {}
+
+/// runs the update of machine `index` and the machines after it
+fn run_updates_from(world: &mut World, index: usize) {
+    let run_update = {
+        let mut batch = world.get_resource_mut::<UpdateBatch>().unwrap();
+        let next = index + 1;
+        batch.next = (next < batch.run_updates.len()).then(|| next);
+        batch.run_updates[index]
+    };
+    run_update(world);
+
+    // the machine didn't run any parallel systems, so the next update wasn't started
+    let next = world.get_resource_mut::<UpdateBatch>().unwrap().next.take();
+    if let Some(next) = next {
+        run_updates_from(world, next);
+    }
+    // the last machines didn't run any parallel systems, so the batch didn't run yet
+    let collected = std::mem::take(&mut world.get_resource_mut::<UpdateBatch>().unwrap().collected);
+    if !collected.is_empty() {
+        run_batch(world, collected);
+    }
+}
+
+/// The `ParallelSystemExecutor` of the update `Schedule`s of the machines in a
+/// `ParallelStatesStage`. Outside of the stage it runs the systems of its `Schedule` on
+/// their own.
+#[derive(Default)]
+pub(crate) struct BatchExecutor {
+    archetype_generation: usize,
+}
+
+impl ParallelSystemExecutor for BatchExecutor {
+    fn rebuild_cached_data(&mut self, _: &[ParallelSystemContainer]) {}
+
+    fn run_systems(&mut self, systems: &mut [ParallelSystemContainer], world: &mut World) {
+        let archetypes = world.archetypes();
+        for archetype in archetypes.iter().skip(self.archetype_generation) {
+            for container in systems.iter_mut() {
+                container.system_mut().new_archetype(archetype);
+            }
+        }
+        self.archetype_generation = archetypes.len();
+
+        let systems = CollectedSystems(systems);
+        let next = world
+            .get_resource_mut::<UpdateBatch>()
+            .and_then(|mut batch| batch.next.take());
+        match next {
+            Some(next) => {
+                let mut batch = world.get_resource_mut::<UpdateBatch>().unwrap();
+                batch.collected.push(systems);
+                run_updates_from(world, next);
+            }
+            None => {
+                let mut collected = world
+                    .get_resource_mut::<UpdateBatch>()
+                    .map_or_else(Vec::new, |mut batch| std::mem::take(&mut batch.collected));
+                collected.push(systems);
+                run_batch(world, collected);
+            }
+        }
+    }
+}
+
+/// runs the systems in waves of systems whose dependencies ran and whose accesses don't
+/// conflict. The systems of a wave run in parallel on the `ComputeTaskPool`.
+fn run_batch(world: &mut World, collected: Vec<CollectedSystems>) {
+    // SAFETY: the executors of the systems wait for this to return and the slices don't
+    // overlap
+    let mut stages: Vec<&mut [ParallelSystemContainer]> = collected
+        .into_iter()
+        .map(|systems| unsafe { &mut *systems.0 })
+        .collect();
+    let mut done: Vec<Vec<bool>> = stages
+        .iter()
+        .map(|systems| systems.iter().map(|system| !system.should_run()).collect())
+        .collect();
+    let mut pending: Vec<(usize, usize)> = done
+        .iter()
+        .enumerate()
+        .flat_map(|(stage, done)| {
+            done.iter()
+                .enumerate()
+                .filter(|(_, done)| !**done)
+                .map(move |(system, _)| (stage, system))
+        })
+        .collect();
+    let task_pool = world
+        .get_resource_or_insert_with(|| ComputeTaskPool(TaskPool::default()))
+        .0
+        .clone();
+
+    while !pending.is_empty() {
+        let mut wave = Vec::new();
+        let mut access = Access::default();
+        for &(stage, index) in pending.iter() {
+            let container = &stages[stage][index];
+            if !container
+                .dependencies()
+                .iter()
+                .all(|dependency| done[stage][*dependency])
+            {
+                continue;
+            }
+            let system = container.system();
+            // systems that aren't `Send` run on this thread on their own
+            if !system.is_send() {
+                if wave.is_empty() {
+                    wave.push((stage, index));
+                }
+                break;
+            }
+            if !system.archetype_component_access().is_compatible(&access) {
+                continue;
+            }
+            access.extend(system.archetype_component_access());
+            wave.push((stage, index));
+        }
+
+        if let [(stage, index)] = wave[..] {
+            stages[stage][index].system_mut().run((), world);
+        } else {
+            let mut systems = Vec::with_capacity(wave.len());
+            for &(stage, index) in wave.iter() {
+                let system: *mut _ = stages[stage][index].system_mut();
+                // SAFETY: every system of the wave is only borrowed once
+                systems.push(unsafe { &mut *system });
+            }
+            let world: &World = world;
+            task_pool.scope(|scope| {
+                for system in systems {
+                    // SAFETY: the accesses of the systems in the wave don't conflict
+                    scope.spawn(async move { unsafe { system.run_unsafe((), world) } });
+                }
+            });
+        }
+
+        for &(stage, index) in wave.iter() {
+            done[stage][index] = true;
+        }
+        pending.retain(|(stage, index)| !done[*stage][*index]);
+    }
+}
@@ -0,0 +1,34 @@
+use std::hash::Hash;
+
+use crate::StateIndex;
+
+/// The bounds every state type needs. `#[derive(ScheduleState)]` implements this for
+/// enums of unit variants and `#[derive(StateData)]` for any other type, both with the
+/// state as its own key.
+///
+/// States that carry data, like `Level(u32)`, implement this themselves and return a
+/// `Key` without the data, so all the `Level(_)` values share their schedules and
+/// settings. The full state can be read from the `CurrentState<S>` resource.
+pub trait StateData: Eq + Hash + Clone + Send + Sync + 'static {
+    /// what the schedules, transitions and other settings of a state are stored under
+    type Key: Eq + Hash + Clone + Send + Sync + 'static;
+
+    /// the key of this state
+    fn key(&self) -> Self::Key;
+
+    /// the name of the state for logging and tracing spans. `#[derive(ScheduleState)]`
+    /// returns the name of the variant. The default is the name of the type.
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// An enum of states. Implement this with `#[derive(ScheduleState)]`, which also
+/// implements `StateIndex` with a `match` on the variants.
+pub trait ScheduleState: StateData<Key = Self> + StateIndex {
+    /// all the states in the order they are declared
+    const VARIANTS: &'static [Self];
+
+    /// the state marked with `#[initial]`, or the first one
+    const INITIAL: Self;
+}
@@ -1,183 +1,1703 @@
 use bevy::{
-    prelude::{IntoExclusiveSystem, Mut, Schedule, Stage, StageLabel, SystemStage, World},
-    utils::HashMap,
+    app::{Events, ManualEventReader},
+    core::Time,
+    ecs::{schedule::GraphNode, system::BoxedSystem},
+    prelude::{
+        In, IntoExclusiveSystem, IntoSystem, Mut, ResMut, Schedule, Stage, StageLabel, SystemStage,
+        World,
+    },
+    utils::{Duration, HashMap, HashSet, Instant},
+};
+use std::{any::TypeId, borrow::Cow, hash::Hash};
+
+use crate::{
+    diagnostics::DriverStats,
+    stage::BatchExecutor,
+    storage::{StateIndex, StateMap},
+    StateData, StateSnapshot,
 };
-use std::hash::Hash;
 
 /// `StageLabel` of `Stage` for `ScheduleStatea` to insert systems into
 #[derive(StageLabel, PartialEq, Eq, Hash, Copy, Clone, Debug)]
 struct StateStage;
 
+/// the `(from, to)` keys a transition is stored under
+type TransitionKey<S> = (<S as StateData>::Key, <S as StateData>::Key);
+
 /// Resource of `Schedule`s attached to states
 pub struct ScheduleStates<S>
 where
-    S: Eq + Hash + Copy + Clone,
+    S: StateData,
 {
     current_state: S,
     first_run: bool,
-    enter: HashMap<S, Schedule>,
-    update: HashMap<S, Schedule>,
-    exit: HashMap<S, Schedule>,
+    enter: StateMap<S::Key, Schedule>,
+    update: StateMap<S::Key, Schedule>,
+    exit: StateMap<S::Key, Schedule>,
+    /// schedules that run around the enter, update and exit `Schedule`s of every state
+    any_enter: Option<Schedule>,
+    any_update: Option<Schedule>,
+    any_exit: Option<Schedule>,
+    timed_transitions: HashMap<S::Key, TimedTransition<S>>,
+    event_transitions: HashMap<S::Key, Vec<Box<dyn EventTransition<S>>>>,
+    /// systems that need to return true for a transition to be applied
+    guards: HashMap<TransitionKey<S>, Vec<ManualSystem<bool>>>,
+    guard_policy: GuardPolicy,
+    transitions: HashMap<TransitionKey<S>, Schedule>,
+    /// the `(from, to)` transition that is currently in progress
+    transitioning: Option<(S, S)>,
+    /// states whose enter `Schedule` runs every frame until the state is ready
+    enter_until_ready: HashSet<S::Key>,
+    /// true while the enter `Schedule` of the current state runs until ready
+    entering: bool,
+    nested_drivers: HashMap<S::Key, Vec<NestedDriver>>,
+    /// transitions the driver has applied
+    observed_transitions: HashSet<TransitionKey<S>>,
+    /// number of times the update `Schedule` ran since the current state was entered
+    ticks_in_state: u32,
+    /// `Time::time_since_startup` when the current state was entered
+    entered_at: Option<Duration>,
+    /// time steps of the states whose update `Schedule` runs on a fixed timestep
+    fixed_timesteps: HashMap<S::Key, Duration>,
+    /// time that hasn't been used up by fixed timestep updates of the current state yet
+    fixed_time: Duration,
+    /// `Time::last_update` of the last frame whose time was added to `fixed_time`, so
+    /// a driver that runs more than once per frame only adds it once
+    fixed_time_updated: Option<Instant>,
+    /// maximum number of times the update `Schedule` can run per driver call
+    max_updates: Option<u32>,
+    catch_up_policy: CatchUpPolicy,
+    /// number of times the update `Schedule` ran in the current driver call
+    updates_this_call: u32,
+    /// counters for `StateDiagnosticsPlugin`. `None` unless the plugin was added.
+    stats: Option<DriverStats<S::Key>>,
+    /// the payload of the transition being applied, until its state has been entered
+    payload: Option<TransitionPayload>,
+    /// true if the update `Schedule`s run their systems in the batch of a
+    /// `ParallelStatesStage`
+    batch_updates: bool,
+}
+
+/// How long a state needs to be active before a timed transition is applied
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransitionDelay {
+    /// time measured with bevy's `Time` resource. The transition will never
+    /// be applied if the `Time` resource does not exist.
+    Time(Duration),
+    /// number of times the update `Schedule` of the state has run
+    Ticks(u32),
+}
+
+impl From<Duration> for TransitionDelay {
+    fn from(duration: Duration) -> Self {
+        TransitionDelay::Time(duration)
+    }
+}
+
+impl From<u32> for TransitionDelay {
+    fn from(ticks: u32) -> Self {
+        TransitionDelay::Ticks(ticks)
+    }
+}
+
+#[derive(Clone)]
+struct TimedTransition<S> {
+    to: S,
+    delay: TransitionDelay,
+}
+
+/// What happens to a transition that was blocked by a guard
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GuardPolicy {
+    /// the requested state is thrown away
+    Drop,
+    /// the requested state is queued again and the guards are checked on the
+    /// next run of the driver
+    Retry,
+}
+
+impl Default for GuardPolicy {
+    fn default() -> Self {
+        GuardPolicy::Drop
+    }
+}
+
+/// Event sent when a guard blocks the transition from `from` to `to`
+#[derive(Clone, Copy, Debug)]
+pub struct TransitionBlocked<S> {
+    pub from: S,
+    pub to: S,
+    pub policy: GuardPolicy,
+}
+
+/// What happens to the fixed timestep time that is left when the driver hits its
+/// update budget
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CatchUpPolicy {
+    /// the time is kept and the updates run on the next calls of the driver
+    Carry,
+    /// the time of all the whole steps that didn't run is thrown away
+    Drop,
+}
+
+impl Default for CatchUpPolicy {
+    fn default() -> Self {
+        CatchUpPolicy::Carry
+    }
+}
+
+/// Event sent when the driver stops running update `Schedule`s because it ran them
+/// `max_updates` times in one call and there were still fixed timestep updates or
+/// transitions left
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateBudgetExceeded<S> {
+    /// the current state when the budget ran out
+    pub state: S,
+    pub max_updates: u32,
+    /// the state that stays queued in `NextState<S>` for the next call of the driver
+    pub next_state: Option<S>,
+}
+
+/// Resource that exists while a multi frame transition is in progress. Systems in the
+/// transition `Schedule` report their progress through this and the transition
+/// finishes once the progress reaches 1.0.
+pub struct TransitionProgress<S> {
+    from: S,
+    to: S,
+    frames: u32,
+    progress: f32,
+}
+
+impl<S: Clone> TransitionProgress<S> {
+    pub(crate) fn new(from: S, to: S) -> Self {
+        TransitionProgress {
+            from,
+            to,
+            frames: 0,
+            progress: 0.0,
+        }
+    }
+
+    /// the state that was exited
+    pub fn from(&self) -> S {
+        self.from.clone()
+    }
+
+    /// the state that will be entered when the transition is complete
+    pub fn to(&self) -> S {
+        self.to.clone()
+    }
+
+    /// number of times the transition `Schedule` has finished running
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// progress of the transition between 0.0 and 1.0
+    pub fn progress(&self) -> f32 {
+        self.progress
+    }
+
+    /// set the progress of the transition. Setting it to 1.0 completes the transition.
+    pub fn set_progress(&mut self, progress: f32) {
+        self.progress = progress.clamp(0.0, 1.0);
+    }
+
+    /// complete the transition after the transition `Schedule` finishes running
+    pub fn finish(&mut self) {
+        self.progress = 1.0;
+    }
+
+    /// returns true once the transition is complete
+    pub fn is_complete(&self) -> bool {
+        self.progress >= 1.0
+    }
+}
+
+/// Resource that exists while the enter `Schedule` of a state that enters until ready
+/// is running. A system needs to call `set_ready` for the update `Schedule` of the
+/// state to start running.
+pub struct EnterReady<S> {
+    state: S,
+    frames: u32,
+    ready: bool,
+}
+
+impl<S: Clone> EnterReady<S> {
+    pub(crate) fn new(state: S) -> Self {
+        EnterReady {
+            state,
+            frames: 0,
+            ready: false,
+        }
+    }
+
+    /// the state that is being entered
+    pub fn state(&self) -> S {
+        self.state.clone()
+    }
+
+    /// number of times the enter `Schedule` has finished running
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// mark the state as ready. The enter `Schedule` will not run again.
+    pub fn set_ready(&mut self) {
+        self.ready = true;
+    }
+
+    /// returns true if the state is ready
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+}
+
+/// A system that marks the state being entered as ready when the piped in value
+/// is true. Chain a system that returns `bool` into this.
+pub fn set_enter_ready<S>(In(ready): In<bool>, mut enter_ready: ResMut<EnterReady<S>>)
+where
+    S: StateData,
+{
+    if ready {
+        enter_ready.set_ready();
+    }
+}
+
+/// Resource that exists while the current state has a fixed timestep. `alpha` is how
+/// far the time is between the last fixed update and the next one, which can be used
+/// to interpolate rendering between the last two updates.
+pub struct InterpolationAlpha<S> {
+    state: S,
+    alpha: f32,
+}
+
+impl<S: Clone> InterpolationAlpha<S> {
+    /// the state with the fixed timestep
+    pub fn state(&self) -> S {
+        self.state.clone()
+    }
+
+    /// between 0.0 right after a fixed update and 1.0 when the next one is due
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+}
+
+/// A system that is run directly on the `World` instead of from a `Stage`, like
+/// transition guards
+pub(crate) struct ManualSystem<Out> {
+    system: BoxedSystem<(), Out>,
+    initialized: bool,
+    archetype_generation: usize,
+}
+
+impl<Out: 'static> ManualSystem<Out> {
+    pub(crate) fn new<Params>(system: impl IntoSystem<(), Out, Params>) -> Self {
+        ManualSystem {
+            system: Box::new(system.system()),
+            initialized: false,
+            archetype_generation: 0,
+        }
+    }
+
+    pub(crate) fn run(&mut self, world: &mut World) -> Out {
+        if !self.initialized {
+            self.system.initialize(world);
+            self.initialized = true;
+        }
+
+        let archetypes = world.archetypes();
+        for archetype in archetypes.iter().skip(self.archetype_generation) {
+            self.system.new_archetype(archetype);
+        }
+        self.archetype_generation = archetypes.generation().value();
+
+        let out = self.system.run((), world);
+        self.system.apply_buffers(world);
+        out
+    }
+}
+
+/// The state type of a driver that was nested in a state
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NestedDriver {
+    type_id: TypeId,
+    type_name: &'static str,
+    /// the `TypeId` of the `ScheduleStates` of the nested state type
+    states_type_id: TypeId,
+}
+
+impl NestedDriver {
+    /// the `TypeId` of the nested state type
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    /// the name of the nested state type
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// returns true if the `ScheduleStates` resource of the nested state type exists
+    pub fn exists(&self, world: &World) -> bool {
+        resource_exists(world, self.states_type_id)
+    }
+}
+
+/// returns true if the resource with the `TypeId` `type_id` exists
+pub(crate) fn resource_exists(world: &World, type_id: TypeId) -> bool {
+    world
+        .components()
+        .get_resource_id(type_id)
+        .and_then(|id| world.archetypes().resource().unique_components().get(id))
+        .map_or(false, |column| !column.is_empty())
+}
+
+/// The kinds of transitions that can be declared on a `ScheduleStates`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum DeclaredTransition {
+    Timed,
+    Event,
+    Guarded,
+    MultiFrame,
+}
+
+/// A transition that is triggered by reading an event
+trait EventTransition<S>: Send + Sync {
+    /// the state the transition goes to
+    fn to(&self) -> S;
+    /// skip all the events that were sent before now
+    fn clear(&mut self, world: &World);
+    /// read the new events and return the target state if one matched
+    fn read(&mut self, world: &World) -> Option<S>;
+}
+
+struct EventTransitionReader<E, S, F> {
+    reader: ManualEventReader<E>,
+    to: S,
+    predicate: F,
+}
+
+impl<E, S, F> EventTransition<S> for EventTransitionReader<E, S, F>
+where
+    E: Send + Sync + 'static,
+    S: Clone + Send + Sync,
+    F: Fn(&E) -> bool + Send + Sync,
+{
+    fn to(&self) -> S {
+        self.to.clone()
+    }
+
+    fn clear(&mut self, world: &World) {
+        if let Some(events) = world.get_resource::<Events<E>>() {
+            self.reader = events.get_reader_current();
+        }
+    }
+
+    fn read(&mut self, world: &World) -> Option<S> {
+        let events = world.get_resource::<Events<E>>()?;
+        let predicate = &self.predicate;
+        // read all the events even after a match so they don't trigger the transition
+        // again the next time the state is active
+        let matches = self.reader.iter(events).filter(|e| predicate(e)).count();
+
+        if matches > 0 {
+            Some(self.to.clone())
+        } else {
+            None
+        }
+    }
 }
 
 impl<S> ScheduleStates<S>
 where
-    S: Eq + Hash + Copy,
+    S: StateData,
 {
     /// creates a new `ScheduleStates` with an `initial_state`
     pub fn new(initial_state: S) -> Self {
         ScheduleStates {
             current_state: initial_state,
             first_run: true,
-            enter: HashMap::default(),
-            update: HashMap::default(),
-            exit: HashMap::default(),
+            enter: StateMap::default(),
+            update: StateMap::default(),
+            exit: StateMap::default(),
+            any_enter: None,
+            any_update: None,
+            any_exit: None,
+            timed_transitions: HashMap::default(),
+            event_transitions: HashMap::default(),
+            guards: HashMap::default(),
+            guard_policy: GuardPolicy::default(),
+            transitions: HashMap::default(),
+            transitioning: None,
+            enter_until_ready: HashSet::default(),
+            entering: false,
+            nested_drivers: HashMap::default(),
+            observed_transitions: HashSet::default(),
+            ticks_in_state: 0,
+            entered_at: None,
+            fixed_timesteps: HashMap::default(),
+            fixed_time: Duration::ZERO,
+            fixed_time_updated: None,
+            max_updates: None,
+            catch_up_policy: CatchUpPolicy::default(),
+            updates_this_call: 0,
+            stats: None,
+            payload: None,
+            batch_updates: false,
+        }
+    }
+
+    /// creates a new `ScheduleStates` with an `initial_state` that keeps the `Schedule`s
+    /// of the states in a `Vec` indexed by `StateIndex` instead of a `HashMap`
+    pub fn new_indexed(initial_state: S) -> Self
+    where
+        S::Key: StateIndex,
+    {
+        ScheduleStates {
+            enter: StateMap::indexed(),
+            update: StateMap::indexed(),
+            exit: StateMap::indexed(),
+            ..Self::new(initial_state)
         }
     }
 
-    /// get the current state
+    /// returns true if the `Schedule`s are kept in a `Vec` indexed by `StateIndex`
+    pub fn is_indexed(&self) -> bool {
+        self.enter.is_indexed() && self.update.is_indexed() && self.exit.is_indexed()
+    }
+
+    /// get the current state. While a multi frame transition is in progress this
+    /// is still the state that was exited.
     pub fn current_state(&self) -> S {
-        self.current_state
+        self.current_state.clone()
     }
 
-    /// adds schedules for a new state
-    fn add_state(&mut self, new_state: S) {
-        let mut schedule = Schedule::default();
-        schedule.add_stage(StateStage, SystemStage::parallel());
-        self.enter.insert(new_state, schedule);
+    /// returns true if the key of the current state is `state`. Use this instead of
+    /// comparing with `current_state` when the states carry data.
+    pub fn is_in(&self, state: &S::Key) -> bool {
+        *state == self.current_state.key()
+    }
 
-        let mut schedule = Schedule::default();
-        schedule.add_stage(StateStage, SystemStage::parallel());
-        self.update.insert(new_state, schedule);
+    /// get the `(from, to)` states of the multi frame transition that is in progress
+    pub fn transitioning(&self) -> Option<(S, S)> {
+        self.transitioning.clone()
+    }
 
-        let mut schedule = Schedule::default();
-        schedule.add_stage(StateStage, SystemStage::parallel());
-        self.exit.insert(new_state, schedule);
+    /// returns true while the enter `Schedule` of the current state is running until
+    /// the state is ready
+    pub fn is_entering(&self) -> bool {
+        self.entering
     }
 
     /// gets the `Schedule` associated with entering `state`. This is used for adding
     /// new systems to that schedule.
-    pub fn with_state_enter(&mut self, state: S) -> &mut SystemStage {
-        if self.enter.get(&state).is_none() {
-            self.add_state(state);
-        }
+    pub fn with_state_enter(&mut self, state: S::Key) -> &mut SystemStage {
         self.enter
-            .get_mut(&state)
-            .unwrap()
+            .get_or_insert_with(state, new_state_schedule)
             .get_stage_mut::<SystemStage>(&StateStage)
             .unwrap()
     }
 
     /// gets the `Schedule` associated with updating `state`. This is used for adding
     /// new systems to that schedule.
-    pub fn with_state_update(&mut self, state: S) -> &mut SystemStage {
-        if self.update.get(&state).is_none() {
-            self.add_state(state);
-        }
+    pub fn with_state_update(&mut self, state: S::Key) -> &mut SystemStage {
+        let batch_updates = self.batch_updates;
         self.update
-            .get_mut(&state)
-            .unwrap()
+            .get_or_insert_with(state, || {
+                let mut schedule = new_state_schedule();
+                if batch_updates {
+                    use_batch_executor(&mut schedule);
+                }
+                schedule
+            })
             .get_stage_mut::<SystemStage>(&StateStage)
             .unwrap()
     }
 
     /// gets the `Schedule` associated with exiting `state`. This is used for adding
     /// new systems to that schedule.
-    pub fn with_state_exit(&mut self, state: S) -> &mut SystemStage {
-        if self.exit.get(&state).is_none() {
-            self.add_state(state);
-        }
+    pub fn with_state_exit(&mut self, state: S::Key) -> &mut SystemStage {
         self.exit
-            .get_mut(&state)
+            .get_or_insert_with(state, new_state_schedule)
+            .get_stage_mut::<SystemStage>(&StateStage)
             .unwrap()
+    }
+
+    /// gets the `Schedule` that runs before the enter `Schedule` of every state. States
+    /// that enter until ready only run it on the first frame of their enter.
+    pub fn with_any_state_enter(&mut self) -> &mut SystemStage {
+        self.any_enter
+            .get_or_insert_with(new_state_schedule)
             .get_stage_mut::<SystemStage>(&StateStage)
             .unwrap()
     }
 
+    /// gets the `Schedule` that runs before the update `Schedule` of every state. It runs
+    /// for every step of a fixed timestep, and its time counts towards the update time
+    /// of the state in the `StateDiagnosticsPlugin`.
+    pub fn with_any_state_update(&mut self) -> &mut SystemStage {
+        self.any_update
+            .get_or_insert_with(new_state_schedule)
+            .get_stage_mut::<SystemStage>(&StateStage)
+            .unwrap()
+    }
+
+    /// gets the `Schedule` that runs after the exit `Schedule` of every state
+    pub fn with_any_state_exit(&mut self) -> &mut SystemStage {
+        self.any_exit
+            .get_or_insert_with(new_state_schedule)
+            .get_stage_mut::<SystemStage>(&StateStage)
+            .unwrap()
+    }
+
+    /// gets the `Schedule` that runs every time the driver runs between exiting `from`
+    /// and entering `to`. Adding a transition `Schedule` turns the transition into a
+    /// multi frame transition that is complete once the systems in the `Schedule` set
+    /// the progress of the `TransitionProgress<S>` resource to 1.0.
+    pub fn with_transition_update(&mut self, from: S::Key, to: S::Key) -> &mut SystemStage {
+        self.transitions
+            .entry((from, to))
+            .or_insert_with(new_state_schedule)
+            .get_stage_mut::<SystemStage>(&StateStage)
+            .unwrap()
+    }
+
+    /// run the enter `Schedule` of `state` every time the driver runs until a system
+    /// marks the `EnterReady<S>` resource as ready. The update `Schedule` of `state`
+    /// doesn't run until then.
+    pub fn enter_until_ready(&mut self, state: S::Key) {
+        self.enter_until_ready.insert(state);
+    }
+
+    /// queue a transition to `to` once `from` has been active for `delay`. The
+    /// timer restarts every time `from` is entered, so leaving `from` early cancels
+    /// the transition. Only one timed transition can exist per state. Adding another
+    /// one replaces the previous one.
+    pub fn add_timed_transition(&mut self, from: S::Key, to: S, delay: impl Into<TransitionDelay>) {
+        self.timed_transitions.insert(
+            from,
+            TimedTransition {
+                to,
+                delay: delay.into(),
+            },
+        );
+    }
+
+    /// removes the timed transition out of `from` if there is one
+    pub fn remove_timed_transition(&mut self, from: S::Key) {
+        self.timed_transitions.remove(&from);
+    }
+
+    /// run the update `Schedule` of `state` once for every `step` of time that passed,
+    /// measured with bevy's `Time` resource. The driver can run it zero or more times
+    /// per call and the `InterpolationAlpha<S>` resource has the time left over. The
+    /// time of a frame is only added once, even if the driver runs more than once in it.
+    pub fn set_fixed_timestep(&mut self, state: S::Key, step: Duration) {
+        assert!(
+            step > Duration::ZERO,
+            "fixed timestep needs to be longer than zero"
+        );
+        self.fixed_timesteps.insert(state, step);
+    }
+
+    /// limit the number of times the update `Schedule` can run in one call of the
+    /// driver, across fixed timestep updates and the transitions it applies. When the
+    /// limit is hit an `UpdateBudgetExceeded<S>` event is sent, transitions that are left
+    /// stay queued and the fixed timestep time that is left is handled by `policy`.
+    pub fn set_update_budget(&mut self, max_updates: u32, policy: CatchUpPolicy) {
+        assert!(
+            max_updates > 0,
+            "update budget needs to allow at least one update"
+        );
+        self.max_updates = Some(max_updates);
+        self.catch_up_policy = policy;
+    }
+
+    /// let the driver run the update `Schedule` any number of times per call again
+    pub fn remove_update_budget(&mut self) {
+        self.max_updates = None;
+    }
+
+    /// run the update `Schedule` of `state` once per driver call again
+    pub fn remove_fixed_timestep(&mut self, state: S::Key) {
+        self.fixed_timesteps.remove(&state);
+    }
+
+    /// transition from `from` to `to` when an event of type `E` is read while `from` is
+    /// the current state. Events sent before `from` was entered are ignored.
+    pub fn add_event_transition<E>(&mut self, from: S::Key, to: S)
+    where
+        E: Send + Sync + 'static,
+    {
+        self.add_event_transition_with::<E, _>(from, to, |_| true);
+    }
+
+    /// like `add_event_transition`, but only events that `predicate` returns true for
+    /// trigger the transition
+    pub fn add_event_transition_with<E, F>(&mut self, from: S::Key, to: S, predicate: F)
+    where
+        E: Send + Sync + 'static,
+        F: Fn(&E) -> bool + Send + Sync + 'static,
+    {
+        self.event_transitions
+            .entry(from)
+            .or_default()
+            .push(Box::new(EventTransitionReader {
+                reader: ManualEventReader::<E>::default(),
+                to,
+                predicate,
+            }));
+    }
+
+    /// add a guard to the transition from `from` to `to`. All the guards of a
+    /// transition need to return true for the transition to be applied.
+    pub fn add_transition_guard<Params>(
+        &mut self,
+        from: S::Key,
+        to: S::Key,
+        guard: impl IntoSystem<(), bool, Params>,
+    ) {
+        self.guards
+            .entry((from, to))
+            .or_default()
+            .push(ManualSystem::new(guard));
+    }
+
+    /// set what happens to transitions that are blocked by a guard. Defaults to
+    /// `GuardPolicy::Drop`.
+    pub fn set_guard_policy(&mut self, policy: GuardPolicy) {
+        self.guard_policy = policy;
+    }
+
+    /// all the states that have schedules or are part of a declared or observed transition
+    pub(crate) fn known_states(&self) -> HashSet<S::Key> {
+        let mut states: HashSet<S::Key> = self
+            .enter
+            .keys()
+            .chain(self.update.keys())
+            .chain(self.exit.keys())
+            .cloned()
+            .collect();
+        states.insert(self.current_state.key());
+        for (from, to, _) in self.declared_transitions() {
+            states.insert(from);
+            states.insert(to);
+        }
+        for (from, to) in self.observed_transitions.iter() {
+            states.insert(from.clone());
+            states.insert(to.clone());
+        }
+        states
+    }
+
+    /// the number of systems in the enter, update and exit `Schedule`s of `state`
+    pub(crate) fn system_counts(&self, state: &S::Key) -> [usize; 3] {
+        [
+            self.enter_system_names(state.clone()).len(),
+            self.update_system_names(state.clone()).len(),
+            self.exit_system_names(state.clone()).len(),
+        ]
+    }
+
+    /// the number of systems in the any state enter, update and exit `Schedule`s
+    pub(crate) fn any_state_system_count(&self) -> usize {
+        [&self.any_enter, &self.any_update, &self.any_exit]
+            .iter()
+            .map(|schedule| system_names(schedule.as_ref()).len())
+            .sum()
+    }
+
+    /// the timed, event, guarded and multi frame transitions
+    pub(crate) fn declared_transitions(&self) -> Vec<(S::Key, S::Key, DeclaredTransition)> {
+        let mut transitions = Vec::new();
+        for (from, timed) in self.timed_transitions.iter() {
+            let to = timed.to.key();
+            transitions.push((from.clone(), to, DeclaredTransition::Timed));
+        }
+        for (from, event_transitions) in self.event_transitions.iter() {
+            for event_transition in event_transitions.iter() {
+                let to = event_transition.to().key();
+                transitions.push((from.clone(), to, DeclaredTransition::Event));
+            }
+        }
+        for (from, to) in self.guards.keys() {
+            transitions.push((from.clone(), to.clone(), DeclaredTransition::Guarded));
+        }
+        for (from, to) in self.transitions.keys() {
+            transitions.push((from.clone(), to.clone(), DeclaredTransition::MultiFrame));
+        }
+        transitions
+    }
+
+    /// the transitions the driver has applied so far
+    pub(crate) fn observed_transitions(&self) -> impl Iterator<Item = &TransitionKey<S>> {
+        self.observed_transitions.iter()
+    }
+
+    /// start recording the counters read by `StateDiagnosticsPlugin`
+    pub(crate) fn enable_stats(&mut self) {
+        self.stats.get_or_insert_with(DriverStats::default);
+    }
+
+    pub(crate) fn stats_mut(&mut self) -> Option<&mut DriverStats<S::Key>> {
+        self.stats.as_mut()
+    }
+
+    /// get all the states that have an enter, update or exit `Schedule`
+    pub fn states(&self) -> Vec<S::Key> {
+        let states: HashSet<S::Key> = self
+            .enter
+            .keys()
+            .chain(self.update.keys())
+            .chain(self.exit.keys())
+            .cloned()
+            .collect();
+        states.into_iter().collect()
+    }
+
+    /// get the names of the systems in the enter `Schedule` of `state`
+    pub fn enter_system_names(&self, state: S::Key) -> Vec<Cow<'static, str>> {
+        system_names(self.enter.get(&state))
+    }
+
+    /// get the names of the systems in the update `Schedule` of `state`
+    pub fn update_system_names(&self, state: S::Key) -> Vec<Cow<'static, str>> {
+        system_names(self.update.get(&state))
+    }
+
+    /// get the names of the systems in the exit `Schedule` of `state`
+    pub fn exit_system_names(&self, state: S::Key) -> Vec<Cow<'static, str>> {
+        system_names(self.exit.get(&state))
+    }
+
+    /// get the drivers that were nested in `state` with `add_nested_driver_to_state`
+    pub fn nested_drivers(&self, state: S::Key) -> &[NestedDriver] {
+        self.nested_drivers
+            .get(&state)
+            .map_or(&[], |nested_drivers| nested_drivers)
+    }
+
+    /// the drivers nested in all of the states
+    pub(crate) fn all_nested_drivers(&self) -> impl Iterator<Item = &NestedDriver> {
+        self.nested_drivers.values().flatten()
+    }
+
+    /// returns true if any drivers were nested in `state`
+    pub fn has_nested_drivers(&self, state: S::Key) -> bool {
+        !self.nested_drivers(state).is_empty()
+    }
+
+    /// captures the current state, whether the state still needs to be entered, the
+    /// progress of timed transitions, any multi frame transition or enter in progress
+    /// and the state queued in `NextState<S>`. The payload of a state queued with
+    /// `NextState::set_with` isn't captured. This is cheap enough to call every frame.
+    pub fn snapshot(&self, world: &World) -> StateSnapshot<S> {
+        let next_state = world.get_resource::<NextState<S>>().unwrap();
+        StateSnapshot {
+            current_state: self.current_state.clone(),
+            first_run: self.first_run,
+            next_state: next_state.state.clone(),
+            guards_passed: next_state.guards_passed,
+            transitioning: self.transitioning.clone(),
+            transition_progress: world
+                .get_resource::<TransitionProgress<S>>()
+                .map(|progress| (progress.frames, progress.progress.to_bits())),
+            entering: self.entering,
+            enter_ready: world
+                .get_resource::<EnterReady<S>>()
+                .map(|enter_ready| (enter_ready.frames, enter_ready.ready)),
+            ticks_in_state: self.ticks_in_state,
+            entered_at: self.entered_at,
+            fixed_time: self.fixed_time,
+        }
+    }
+
+    /// restores a snapshot taken with `snapshot`. No schedules are run. If `replay_enter`
+    /// is true the enter `Schedule` of the restored state runs from the start on the next
+    /// run of the driver and any multi frame transition or enter in the snapshot is dropped.
+    ///
+    /// The `TransitionProgress<S>`, `EnterReady<S>` and `CurrentState<S>` resources are
+    /// added or removed to match the restored state. Events sent before the restore don't
+    /// trigger the event transitions of the restored state. The payloads of the queued
+    /// state and of a transition that was being applied are dropped, so the enter
+    /// systems of the restored state don't find a `TransitionContext`.
+    pub fn restore(&mut self, world: &mut World, snapshot: StateSnapshot<S>, replay_enter: bool) {
+        self.current_state = snapshot.current_state;
+        let mut next_state = world.get_resource_mut::<NextState<S>>().unwrap();
+        next_state.state = snapshot.next_state;
+        next_state.payload = None;
+        next_state.guards_passed = snapshot.guards_passed;
+        if let Some(payload) = self.payload.take() {
+            (payload.remove)(world);
+        }
+        if replay_enter {
+            self.first_run = true;
+            self.transitioning = None;
+            self.entering = false;
+            self.ticks_in_state = 0;
+            self.entered_at = None;
+            self.fixed_time = Duration::ZERO;
+        } else {
+            self.first_run = snapshot.first_run;
+            self.transitioning = snapshot.transitioning;
+            self.entering = snapshot.entering;
+            self.ticks_in_state = snapshot.ticks_in_state;
+            self.entered_at = snapshot.entered_at;
+            self.fixed_time = snapshot.fixed_time;
+        }
+
+        match self.transitioning.clone() {
+            Some((from, to)) => {
+                let mut progress = TransitionProgress::new(from, to);
+                if let Some((frames, bits)) = snapshot.transition_progress {
+                    progress.frames = frames;
+                    progress.progress = f32::from_bits(bits);
+                }
+                world.insert_resource(progress);
+            }
+            None => {
+                world.remove_resource::<TransitionProgress<S>>();
+            }
+        }
+        if self.entering {
+            let mut enter_ready = EnterReady::new(self.current_state.clone());
+            if let Some((frames, ready)) = snapshot.enter_ready {
+                enter_ready.frames = frames;
+                enter_ready.ready = ready;
+            }
+            world.insert_resource(enter_ready);
+        } else {
+            world.remove_resource::<EnterReady<S>>();
+        }
+        // the exited state has no `CurrentState` while a transition is in progress
+        if carries_data::<S>() && !self.first_run && self.transitioning.is_none() {
+            world.insert_resource(CurrentState(self.current_state.clone()));
+        } else {
+            world.remove_resource::<CurrentState<S>>();
+        }
+        self.clear_event_transitions(world);
+    }
+
     /// runs the update `Schedule` associated with state `S`
     pub fn run_update(&mut self, world: &mut World, state: S) {
-        self.update.get_mut(&state).unwrap().run(world);
+        #[cfg(feature = "trace")]
+        let span = schedule_span("update", &state);
+        #[cfg(feature = "trace")]
+        let _guard = span.enter();
+
+        let key = state.key();
+        match self.stats.as_mut() {
+            Some(stats) => {
+                let start = Instant::now();
+                let ran_any = run_schedule(self.any_update.as_mut(), world);
+                if run_schedule(self.update.get_mut(&key), world) || ran_any {
+                    stats.record_update(key.clone(), start.elapsed());
+                }
+            }
+            None => {
+                run_schedule(self.any_update.as_mut(), world);
+                run_schedule(self.update.get_mut(&key), world);
+            }
+        }
+        if self.is_in(&key) {
+            self.ticks_in_state = self.ticks_in_state.saturating_add(1);
+        }
     }
 
     /// runs the entering `Schedule` associated with state `S`
     pub fn run_enter(&mut self, world: &mut World, state: S) {
-        self.enter.get_mut(&state).unwrap().run(world);
+        self.run_enter_schedules(world, state, true);
+    }
+
+    /// runs the entering `Schedule` of `state`, after the any state enter `Schedule` if
+    /// `run_any` is true
+    fn run_enter_schedules(&mut self, world: &mut World, state: S, run_any: bool) {
+        #[cfg(feature = "trace")]
+        let span = schedule_span("enter", &state);
+        #[cfg(feature = "trace")]
+        let _guard = span.enter();
+
+        if run_any {
+            run_schedule(self.any_enter.as_mut(), world);
+        }
+        run_schedule(self.enter.get_mut(&state.key()), world);
     }
 
     /// runs the exiting `Schedule` associated with state `S`
     pub fn run_exit(&mut self, world: &mut World, state: S) {
-        self.exit.get_mut(&state).unwrap().run(world);
+        #[cfg(feature = "trace")]
+        let span = schedule_span("exit", &state);
+        #[cfg(feature = "trace")]
+        let _guard = span.enter();
+
+        run_schedule(self.exit.get_mut(&state.key()), world);
+        run_schedule(self.any_exit.as_mut(), world);
+    }
+
+    /// adds the time since it was last added to the time of the fixed timestep of the
+    /// current state. Nothing is added if the driver already ran in this frame.
+    fn accumulate_fixed_time(&mut self, world: &World) {
+        if !self.fixed_timesteps.contains_key(&self.current_state.key()) {
+            return;
+        }
+        let time = match world.get_resource::<Time>() {
+            Some(time) => time,
+            None => return,
+        };
+        let last_update = match time.last_update() {
+            Some(last_update) => last_update,
+            None => return,
+        };
+        self.fixed_time += match self.fixed_time_updated {
+            Some(updated) => last_update.saturating_duration_since(updated),
+            None => time.delta(),
+        };
+        self.fixed_time_updated = Some(last_update);
+    }
+
+    /// runs the update `Schedule` of the current state. States with a fixed timestep
+    /// run it once for every step of accumulated time and stop early when a state is
+    /// queued in `NextState<S>`.
+    fn update_current_state(&mut self, world: &mut World) {
+        let current_state = self.current_state.clone();
+        let step = match self.fixed_timesteps.get(&current_state.key()) {
+            Some(step) => *step,
+            None => {
+                if world.contains_resource::<InterpolationAlpha<S>>() {
+                    world.remove_resource::<InterpolationAlpha<S>>();
+                }
+                self.updates_this_call += 1;
+                self.run_update(world, current_state);
+                return;
+            }
+        };
+
+        while self.fixed_time >= step {
+            if !self.has_update_budget() {
+                if self.catch_up_policy == CatchUpPolicy::Drop {
+                    let left_over = self.fixed_time.as_nanos() % step.as_nanos();
+                    self.fixed_time = Duration::from_nanos(left_over as u64);
+                }
+                self.send_budget_exceeded(world, None);
+                break;
+            }
+
+            self.fixed_time -= step;
+            self.updates_this_call += 1;
+            self.run_update(world, current_state.clone());
+            let queued = world
+                .get_resource::<NextState<S>>()
+                .map_or(false, |n| n.state.is_some());
+            if queued {
+                break;
+            }
+        }
+
+        world.insert_resource(InterpolationAlpha {
+            state: current_state,
+            alpha: (self.fixed_time.as_secs_f64() / step.as_secs_f64()).min(1.0) as f32,
+        });
+    }
+
+    /// returns true if the update `Schedule` can run again in this driver call
+    fn has_update_budget(&self) -> bool {
+        self.max_updates
+            .map_or(true, |max_updates| self.updates_this_call < max_updates)
+    }
+
+    fn send_budget_exceeded(&self, world: &mut World, next_state: Option<S>) {
+        let event = UpdateBudgetExceeded {
+            state: self.current_state.clone(),
+            max_updates: self.max_updates.unwrap_or_default(),
+            next_state,
+        };
+        if let Some(mut events) = world.get_resource_mut::<Events<UpdateBudgetExceeded<S>>>() {
+            events.send(event);
+        }
+    }
+
+    /// returns the target of the timed transition out of the current state if its
+    /// delay has elapsed
+    fn elapsed_timed_transition(&self, world: &World) -> Option<S> {
+        let timed = self.timed_transitions.get(&self.current_state.key())?;
+        let elapsed = match timed.delay {
+            TransitionDelay::Ticks(ticks) => self.ticks_in_state >= ticks,
+            TransitionDelay::Time(duration) => {
+                match (self.entered_at, world.get_resource::<Time>()) {
+                    (Some(entered_at), Some(time)) => {
+                        time.time_since_startup().saturating_sub(entered_at) >= duration
+                    }
+                    _ => false,
+                }
+            }
+        };
+
+        if elapsed {
+            Some(timed.to.clone())
+        } else {
+            None
+        }
+    }
+
+    /// reads the events for the event transitions out of the current state and
+    /// returns the target of the first one that matched
+    fn read_event_transitions(&mut self, world: &World) -> Option<S> {
+        let key = self.current_state.key();
+        let event_transitions = self.event_transitions.get_mut(&key)?;
+        let mut next_state = None;
+        for event_transition in event_transitions.iter_mut() {
+            let to = event_transition.read(world);
+            next_state = next_state.or(to);
+        }
+        next_state
+    }
+
+    /// runs the guards for the transition from the current state to `next_state`, unless
+    /// they already passed before the transition was requeued for the update budget.
+    /// Returns `next_state` if the transition is allowed. Otherwise a `TransitionBlocked`
+    /// event is sent and `retry` is set when the policy is `GuardPolicy::Retry`.
+    fn guard_transition(
+        &mut self,
+        world: &mut World,
+        next_state: Option<S>,
+        guards_passed: bool,
+        retry: &mut Option<S>,
+    ) -> Option<S> {
+        let to = next_state?;
+        if guards_passed {
+            return Some(to);
+        }
+        let from = self.current_state.clone();
+        let key = (from.key(), to.key());
+        let allowed = match self.guards.get_mut(&key) {
+            Some(guards) => guards.iter_mut().all(|guard| guard.run(world)),
+            None => true,
+        };
+        if allowed {
+            return Some(to);
+        }
+
+        let policy = self.guard_policy;
+        if let Some(mut events) = world.get_resource_mut::<Events<TransitionBlocked<S>>>() {
+            events.send(TransitionBlocked {
+                from,
+                to: to.clone(),
+                policy,
+            });
+        }
+        if policy == GuardPolicy::Retry {
+            *retry = Some(to);
+        } else {
+            self.payload = None;
+        }
+        None
+    }
+
+    /// runs the exit `Schedule` of the current state and removes its `CurrentState<S>`
+    fn exit_current_state(&mut self, world: &mut World) {
+        self.run_exit(world, self.current_state.clone());
+        if carries_data::<S>() {
+            world.remove_resource::<CurrentState<S>>();
+        }
+    }
+
+    /// exits the current state when the state the machine is nested in exits. The `from`
+    /// state of a multi frame transition in progress was already exited, so only the
+    /// transition is dropped. An enter in progress is dropped too.
+    fn exit_nested(&mut self, world: &mut World) {
+        if self.transitioning.take().is_some() {
+            world.remove_resource::<TransitionProgress<S>>();
+        } else {
+            self.exit_current_state(world);
+        }
+        if self.entering {
+            self.entering = false;
+            world.remove_resource::<EnterReady<S>>();
+        }
+        if let Some(payload) = self.payload.take() {
+            (payload.remove)(world);
+        }
+    }
+
+    /// exits the current state and enters `next_state`. Returns false if a multi frame
+    /// transition or enter was started and is not complete yet.
+    fn transition_to(&mut self, world: &mut World, next_state: S) -> bool {
+        let current_state = self.current_state.clone();
+        let key = (current_state.key(), next_state.key());
+        self.first_run = false;
+        self.observed_transitions.insert(key.clone());
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record_transition();
+        }
+        self.exit_current_state(world);
+
+        if self.transitions.contains_key(&key) {
+            self.transitioning = Some((current_state.clone(), next_state.clone()));
+            world.insert_resource(TransitionProgress::new(current_state, next_state));
+            if !self.run_transition(world) {
+                return false;
+            }
+        } else {
+            self.current_state = next_state;
+        }
+
+        self.enter_current_state(world)
+    }
+
+    /// runs the `Schedule` of the transition in progress. Once the transition is
+    /// complete the state it transitioned to becomes the current state. Returns true
+    /// if the transition is complete.
+    fn run_transition(&mut self, world: &mut World) -> bool {
+        let (from, to) = self.transitioning.clone().unwrap();
+        let key = (from.key(), to.key());
+        self.transitions.get_mut(&key).unwrap().run(world);
+
+        let complete = match world.get_resource_mut::<TransitionProgress<S>>() {
+            Some(mut progress) => {
+                progress.frames += 1;
+                progress.is_complete()
+            }
+            // treat the transition as complete if the resource was removed
+            None => true,
+        };
+        if complete {
+            world.remove_resource::<TransitionProgress<S>>();
+            self.transitioning = None;
+            self.current_state = to;
+        }
+
+        complete
+    }
+
+    /// runs the enter `Schedule` of the current state with the `TransitionContext` of
+    /// the payload of the transition, if it has one. The context is removed once the
+    /// state has been entered. Returns true once the state has been entered.
+    fn enter_current_state(&mut self, world: &mut World) -> bool {
+        if let Some(insert) = self.payload.as_mut().and_then(|p| p.insert.take()) {
+            insert(world);
+        }
+        let entered = self.run_current_enter(world);
+        if entered {
+            if let Some(payload) = self.payload.take() {
+                (payload.remove)(world);
+            }
+        }
+        entered
+    }
+
+    /// resets the time in the current state and the event readers of its event
+    /// transitions. This runs once when the enter of the current state starts.
+    fn start_enter(&mut self, world: &mut World) {
+        if carries_data::<S>() {
+            world.insert_resource(CurrentState(self.current_state.clone()));
+        }
+        self.ticks_in_state = 0;
+        self.fixed_time = Duration::ZERO;
+        let time = world.get_resource::<Time>();
+        self.entered_at = time.map(|time| time.time_since_startup());
+        self.fixed_time_updated = time.and_then(|time| time.last_update());
+        self.clear_event_transitions(world);
+    }
+
+    /// skips the events that were sent so far for the event transitions out of the
+    /// current state
+    fn clear_event_transitions(&mut self, world: &World) {
+        let key = self.current_state.key();
+        if let Some(event_transitions) = self.event_transitions.get_mut(&key) {
+            for event_transition in event_transitions.iter_mut() {
+                event_transition.clear(world);
+            }
+        }
+    }
+
+    /// runs the enter `Schedule` of the current state. States that enter until ready
+    /// run it again every time this is called until the `EnterReady<S>` resource is
+    /// set to ready. Returns true once the state has been entered.
+    fn run_current_enter(&mut self, world: &mut World) -> bool {
+        let current_state = self.current_state.clone();
+        if !self.enter_until_ready.contains(&current_state.key()) {
+            self.start_enter(world);
+            self.run_enter(world, current_state);
+            return true;
+        }
+
+        // the any state enter `Schedule` only runs on the first frame of the enter
+        let starting = !self.entering;
+        if starting {
+            self.entering = true;
+            world.insert_resource(EnterReady::new(current_state.clone()));
+            self.start_enter(world);
+        }
+        self.run_enter_schedules(world, current_state, starting);
+
+        let ready = match world.get_resource_mut::<EnterReady<S>>() {
+            Some(mut ready) => {
+                ready.frames += 1;
+                ready.is_ready()
+            }
+            // treat the state as ready if the resource was removed
+            None => true,
+        };
+        if ready {
+            world.remove_resource::<EnterReady<S>>();
+            self.entering = false;
+        }
+
+        ready
+    }
+
+    /// continues a multi frame transition or enter that was started on a previous
+    /// run of the driver. Returns true if there is nothing left to finish.
+    fn finish_entering(&mut self, world: &mut World) -> bool {
+        if self.transitioning.is_some() {
+            if !self.run_transition(world) {
+                return false;
+            }
+            return self.enter_current_state(world);
+        }
+
+        if self.entering {
+            return self.enter_current_state(world);
+        }
+
+        true
+    }
+
+    /// takes the state queued in `NextState<S>` and keeps its payload until the state
+    /// has been entered. Also returns true if the guards of the transition already passed.
+    fn take_next_state(&mut self, world: &mut World) -> (Option<S>, bool) {
+        let mut n = world.get_resource_mut::<NextState<S>>().unwrap();
+        let next_state = n.state.take();
+        let guards_passed = std::mem::take(&mut n.guards_passed);
+        if next_state.is_some() {
+            self.payload = n.payload.take();
+        }
+        (next_state, guards_passed)
+    }
+
+    /// queues `next_state` and the payload that was taken with it in `NextState<S>`
+    /// unless another state was queued already. `guards_passed` skips the guards of the
+    /// transition when it is taken again.
+    fn requeue_next_state(
+        &mut self,
+        world: &mut World,
+        next_state: Option<S>,
+        guards_passed: bool,
+    ) {
+        if next_state.is_none() {
+            return;
+        }
+        let payload = self.payload.take();
+        let mut n = world.get_resource_mut::<NextState<S>>().unwrap();
+        if n.state.is_none() {
+            n.state = next_state;
+            n.payload = payload;
+            n.guards_passed = guards_passed;
+        }
+    }
+
+    /// the state an event or timed transition out of the current state goes to. Event
+    /// transitions take priority over timed transitions and don't have a payload. Only
+    /// one timed transition is taken per driver call, so timed transitions that form a
+    /// cycle continue on the next call instead of looping forever.
+    fn triggered_transition(&mut self, world: &World, timed_taken: &mut bool) -> Option<S> {
+        let mut next_state = self.read_event_transitions(world);
+        if next_state.is_none() && !*timed_taken {
+            next_state = self.elapsed_timed_transition(world);
+            *timed_taken = next_state.is_some();
+        }
+        if next_state.is_some() {
+            self.payload = None;
+        }
+        next_state
+    }
+
+    /// enters the current state if it hasn't been entered yet. Returns false if it
+    /// enters until ready and isn't ready yet.
+    fn enter_first_run(&mut self, world: &mut World) -> bool {
+        if !self.first_run {
+            return true;
+        }
+        self.first_run = false;
+        self.enter_current_state(world)
+    }
+
+    /// applies transitions and runs the update `Schedule` of the current state until no
+    /// more transitions are queued. This is what `driver` runs.
+    pub(crate) fn drive(&mut self, world: &mut World) {
+        // nothing else runs until a multi frame transition or enter is complete
+        if !self.finish_entering(world) {
+            if let Some(stats) = self.stats.as_mut() {
+                stats.record_driver_call(0);
+            }
+            return;
+        }
+
+        self.accumulate_fixed_time(world);
+        self.updates_this_call = 0;
+
+        // a blocked transition that should be requested again on the next run
+        let mut retry = None;
+        let (next_state, guards_passed) = self.take_next_state(world);
+        let mut next_state = self.guard_transition(world, next_state, guards_passed, &mut retry);
+
+        let mut timed_taken = false;
+        let mut loop_iterations = 0;
+        loop {
+            loop_iterations += 1;
+            if let Some(next_state) = next_state {
+                retry = None;
+                if !self.transition_to(world, next_state) {
+                    break;
+                }
+            }
+
+            // TODO: check if this might be a bit buggy if a user queue a next state before the first run of the driver
+            if !self.enter_first_run(world) {
+                break;
+            }
+
+            self.update_current_state(world);
+
+            // a state queued by a system takes priority over event and timed transitions
+            let (queued, guards_passed) = self.take_next_state(world);
+            next_state = queued;
+            if next_state.is_none() {
+                next_state = self.triggered_transition(world, &mut timed_taken);
+            }
+            next_state = self.guard_transition(world, next_state, guards_passed, &mut retry);
+
+            if next_state.is_none() {
+                break;
+            }
+            // the transition is applied on the next call, so the update of the
+            // state it goes to doesn't run over budget
+            if !self.has_update_budget() {
+                self.send_budget_exceeded(world, next_state.clone());
+                self.requeue_next_state(world, next_state, true);
+                break;
+            }
+        }
+
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record_driver_call(loop_iterations);
+        }
+        self.requeue_next_state(world, retry, false);
+    }
+
+    /// applies the queued, event or timed transition if there is one and enters the
+    /// current state if it hasn't been entered yet. This is what `apply_transitions` runs.
+    pub(crate) fn apply_transitions(&mut self, world: &mut World) {
+        if !self.finish_entering(world) {
+            return;
+        }
+
+        let mut retry = None;
+        let (mut next_state, guards_passed) = self.take_next_state(world);
+        if next_state.is_none() {
+            next_state = self.triggered_transition(world, &mut false);
+        }
+        if let Some(next_state) =
+            self.guard_transition(world, next_state, guards_passed, &mut retry)
+        {
+            if !self.transition_to(world, next_state) {
+                return;
+            }
+        }
+        self.enter_first_run(world);
+        self.requeue_next_state(world, retry, false);
+    }
+
+    /// returns true once the current state has been entered and no multi frame
+    /// transition or enter is in progress
+    pub(crate) fn has_entered(&self) -> bool {
+        !self.first_run && !self.entering && self.transitioning.is_none()
+    }
+
+    /// runs the update `Schedule` of the current state once it has been entered and no
+    /// multi frame transition is in progress. This is what `run_state_update` runs.
+    pub(crate) fn update_entered_state(&mut self, world: &mut World) {
+        if !self.has_entered() {
+            return;
+        }
+        self.accumulate_fixed_time(world);
+        self.updates_this_call = 0;
+        self.update_current_state(world);
     }
 
     /// add driver for states `T` to state `S`. This adds the
     /// systems responsible for running the correct enter and exit
     /// systems
-    pub fn add_nested_driver_to_state<T>(&mut self, state: S)
+    pub fn add_nested_driver_to_state<T>(&mut self, state: S::Key)
     where
-        T: Eq + Hash + Copy + Send + Sync + 'static,
+        T: StateData,
     {
-        self.with_state_enter(state)
+        self.nested_drivers
+            .entry(state.clone())
+            .or_default()
+            .push(NestedDriver {
+                type_id: TypeId::of::<T>(),
+                type_name: std::any::type_name::<T>(),
+                states_type_id: TypeId::of::<ScheduleStates<T>>(),
+            });
+        self.with_state_enter(state.clone())
             .add_system(driver_run_enter::<T>.exclusive_system());
-        self.with_state_update(state)
+        self.with_state_update(state.clone())
             .add_system(driver::<T>.exclusive_system());
         self.with_state_exit(state)
             .add_system(driver_run_exit::<T>.exclusive_system());
     }
+
+    /// run the systems of the update `Schedule`s in the batch of a `ParallelStatesStage`
+    pub(crate) fn batch_updates(&mut self) {
+        if self.batch_updates {
+            return;
+        }
+        self.batch_updates = true;
+        for schedule in self.update.values_mut() {
+            use_batch_executor(schedule);
+        }
+    }
+}
+
+/// returns true if the states of `S` carry data, so their key is a different type
+fn carries_data<S: StateData>() -> bool {
+    TypeId::of::<S::Key>() != TypeId::of::<S>()
+}
+
+/// creates the span for running the enter, update or exit `Schedule` of `state`
+#[cfg(feature = "trace")]
+fn schedule_span<S: StateData>(schedule: &'static str, state: &S) -> bevy::utils::tracing::Span {
+    bevy::utils::tracing::info_span!(
+        "state schedule",
+        schedule,
+        state_type = std::any::type_name::<S>(),
+        state = state.name()
+    )
+}
+
+/// runs the parallel systems of an update `Schedule` with the `BatchExecutor`
+fn use_batch_executor(schedule: &mut Schedule) {
+    schedule
+        .get_stage_mut::<SystemStage>(&StateStage)
+        .unwrap()
+        .set_executor(Box::new(BatchExecutor::default()));
+}
+
+/// creates a `Schedule` with the `Stage` that `ScheduleStates` adds systems to
+fn new_state_schedule() -> Schedule {
+    let mut schedule = Schedule::default();
+    schedule.add_stage(StateStage, SystemStage::parallel());
+    schedule
+}
+
+/// runs `schedule` if it exists and has any systems. Returns true if it ran.
+fn run_schedule(schedule: Option<&mut Schedule>, world: &mut World) -> bool {
+    match schedule {
+        Some(schedule) if !is_empty(schedule) => {
+            schedule.run(world);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// returns true if there are no systems in a `Schedule` created by `ScheduleStates`
+fn is_empty(schedule: &Schedule) -> bool {
+    match schedule.get_stage::<SystemStage>(&StateStage) {
+        Some(stage) => {
+            stage.parallel_systems().is_empty()
+                && stage.exclusive_at_start_systems().is_empty()
+                && stage.exclusive_before_commands_systems().is_empty()
+                && stage.exclusive_at_end_systems().is_empty()
+        }
+        None => true,
+    }
+}
+
+/// the names of all the systems in a `Schedule` created by `ScheduleStates`
+fn system_names(schedule: Option<&Schedule>) -> Vec<Cow<'static, str>> {
+    let stage = match schedule.and_then(|schedule| schedule.get_stage::<SystemStage>(&StateStage)) {
+        Some(stage) => stage,
+        None => return Vec::new(),
+    };
+
+    let mut names = Vec::new();
+    names.extend(stage.exclusive_at_start_systems().iter().map(|s| s.name()));
+    names.extend(stage.parallel_systems().iter().map(|s| s.name()));
+    names.extend(
+        stage
+            .exclusive_before_commands_systems()
+            .iter()
+            .map(|s| s.name()),
+    );
+    names.extend(stage.exclusive_at_end_systems().iter().map(|s| s.name()));
+    names
+}
+
+/// Resource with the current state, including the data it carries. It only exists for
+/// states that carry data, i.e. whose `StateData::Key` is a different type. It is added
+/// when a state is entered and removed after its exit `Schedule` ran, so the enter,
+/// update and exit systems of a state can read its data.
+pub struct CurrentState<S>(S);
+
+impl<S> CurrentState<S> {
+    pub fn get(&self) -> &S {
+        &self.0
+    }
 }
 
 /// Resource for queuing a state change. Only one state can
 /// be queued at a time. If another state is queued then it will
 /// overwrite the previously queued state.
-pub struct NextState<S: Copy>(Option<S>);
+pub struct NextState<S> {
+    state: Option<S>,
+    payload: Option<TransitionPayload>,
+    /// true if the queued state was requeued by the driver after its guards passed
+    guards_passed: bool,
+}
 
-impl<S: Copy> Default for NextState<S> {
+impl<S> Default for NextState<S> {
     fn default() -> Self {
-        NextState(None)
+        NextState {
+            state: None,
+            payload: None,
+            guards_passed: false,
+        }
     }
 }
 
-impl<S: Copy> NextState<S> {
+impl<S> NextState<S> {
     pub fn set(&mut self, next_state: S) {
-        self.0 = Some(next_state);
+        self.state = Some(next_state);
+        self.payload = None;
+        self.guards_passed = false;
+    }
+
+    /// queue `next_state` with a `payload` that the enter systems of `next_state` can
+    /// read from the `TransitionContext<S, P>` resource. The resource is removed once
+    /// the state has been entered.
+    ///
+    /// Only states queued in `NextState` carry a payload. States entered through event
+    /// or timed transitions don't insert a `TransitionContext`, and a snapshot doesn't
+    /// capture the payload, so the `TransitionContext` doesn't exist after a restore.
+    pub fn set_with<P>(&mut self, next_state: S, payload: P)
+    where
+        S: StateData,
+        P: Send + Sync + 'static,
+    {
+        let context = TransitionContext {
+            state: next_state.clone(),
+            payload,
+        };
+        self.state = Some(next_state);
+        self.guards_passed = false;
+        self.payload = Some(TransitionPayload {
+            insert: Some(Box::new(move |world: &mut World| {
+                world.insert_resource(context)
+            })),
+            remove: |world| {
+                world.remove_resource::<TransitionContext<S, P>>();
+            },
+        });
+    }
+}
+
+/// inserts the `TransitionContext` resource of a payload
+type InsertContext = Box<dyn FnOnce(&mut World) + Send + Sync>;
+
+/// A payload queued with `NextState::set_with`, with its type erased
+struct TransitionPayload {
+    /// inserts the `TransitionContext` resource. `None` once it was inserted.
+    insert: Option<InsertContext>,
+    /// removes the `TransitionContext` resource
+    remove: fn(&mut World),
+}
+
+/// Resource that exists while the state a payload was queued for with
+/// `NextState::set_with` is being entered
+pub struct TransitionContext<S, P> {
+    state: S,
+    payload: P,
+}
+
+impl<S: Clone, P> TransitionContext<S, P> {
+    /// the state that is being entered
+    pub fn state(&self) -> S {
+        self.state.clone()
+    }
+
+    /// the payload the state was queued with
+    pub fn payload(&self) -> &P {
+        &self.payload
+    }
+}
+
+/// A system that queues the piped in state as the next state. Chain a system
+/// that returns `Option<S>` into this to change state without taking a
+/// `ResMut<NextState<S>>`. `None` leaves the queued state untouched.
+pub fn set_next_state<S>(In(next_state): In<Option<S>>, mut n: ResMut<NextState<S>>)
+where
+    S: StateData,
+{
+    if let Some(next_state) = next_state {
+        n.set(next_state);
     }
 }
 
 /// The system responsible for running the state schedules.
 pub fn driver<S>(world: &mut World)
 where
-    S: Eq + Hash + Copy + Send + Sync + 'static,
+    S: StateData,
 {
-    let mut next_state = None;
-    world.resource_scope(|_world, mut n: Mut<NextState<S>>| {
-        next_state = n.0.take();
-    });
+    #[cfg(feature = "trace")]
+    let span =
+        bevy::utils::tracing::info_span!("state driver", state_type = std::any::type_name::<S>());
+    #[cfg(feature = "trace")]
+    let _guard = span.enter();
 
-    world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| loop {
-        if let Some(next_state) = next_state {
-            let current_state = state.current_state;
-            state.run_exit(world, current_state);
-            state.current_state = next_state;
-            state.run_enter(world, next_state);
-            state.first_run = false;
-        }
+    world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| {
+        state.drive(world);
+    });
+}
 
-        let current_state = state.current_state;
-        // TODO: check if this might be a bit buggy if a user queue a next state before the first run of the driver
-        if state.first_run {
-            state.first_run = false;
-            state.run_enter(world, current_state);
-        }
+/// An exclusive system that only applies the transitions of `S` and enters the current
+/// state on its first run. Use this with `run_state_update` instead of `driver` to apply
+/// transitions and run the update `Schedule` in different stages. States queued during
+/// the update are applied the next time this runs.
+pub fn apply_transitions<S>(world: &mut World)
+where
+    S: StateData,
+{
+    #[cfg(feature = "trace")]
+    let span = bevy::utils::tracing::info_span!(
+        "state transitions",
+        state_type = std::any::type_name::<S>()
+    );
+    #[cfg(feature = "trace")]
+    let _guard = span.enter();
 
-        state.run_update(world, current_state);
+    world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| {
+        state.apply_transitions(world);
+    });
+}
 
-        // check if the state queued a new state internally
-        world.resource_scope(|_world, mut n: Mut<NextState<S>>| {
-            next_state = n.0.take();
-        });
+/// An exclusive system that only runs the update `Schedule` of the current state of `S`.
+/// It doesn't run until `apply_transitions` has entered the state.
+pub fn run_state_update<S>(world: &mut World)
+where
+    S: StateData,
+{
+    #[cfg(feature = "trace")]
+    let span =
+        bevy::utils::tracing::info_span!("state update", state_type = std::any::type_name::<S>());
+    #[cfg(feature = "trace")]
+    let _guard = span.enter();
 
-        if next_state.is_none() {
-            break;
-        }
+    world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| {
+        state.update_entered_state(world);
     });
 }
 
@@ -185,11 +1705,19 @@ where
 /// Used by nested states.
 pub fn driver_run_enter<S>(world: &mut World)
 where
-    S: Eq + Hash + Copy + Send + Sync + 'static,
+    S: StateData,
 {
+    #[cfg(feature = "trace")]
+    let span =
+        bevy::utils::tracing::info_span!("state enter", state_type = std::any::type_name::<S>());
+    #[cfg(feature = "trace")]
+    let _guard = span.enter();
+
     world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| {
-        let current_state = state.current_state();
-        state.run_enter(world, current_state);
+        // the state is entered here, so the nested driver shouldn't enter it again. A
+        // state that enters until ready keeps entering on the runs of the nested driver.
+        state.first_run = false;
+        state.enter_current_state(world);
     });
 }
 
@@ -197,10 +1725,15 @@ where
 ///Used by nested states.
 pub fn driver_run_exit<S>(world: &mut World)
 where
-    S: Eq + Hash + Copy + Send + Sync + 'static,
+    S: StateData,
 {
+    #[cfg(feature = "trace")]
+    let span =
+        bevy::utils::tracing::info_span!("state exit", state_type = std::any::type_name::<S>());
+    #[cfg(feature = "trace")]
+    let _guard = span.enter();
+
     world.resource_scope(|world, mut state: Mut<ScheduleStates<S>>| {
-        let current_state = state.current_state();
-        state.run_exit(world, current_state);
+        state.exit_nested(world);
     });
 }
@@ -0,0 +1,88 @@
+use bevy::utils::HashMap;
+use std::hash::Hash;
+
+/// Maps every state of `Self` to a dense index, so `ScheduleStates::new_indexed` can keep
+/// the `Schedule`s of the states in a `Vec` instead of looking them up in a `HashMap`.
+pub trait StateIndex {
+    /// the number of states. `index` needs to return a value less than this.
+    const COUNT: usize;
+
+    /// the index of this state between 0 and `COUNT`
+    fn index(&self) -> usize;
+}
+
+/// Values attached to states, stored in a `HashMap` or in a `Vec` by `StateIndex`
+pub(crate) enum StateMap<S, T> {
+    Hashed(HashMap<S, T>),
+    Indexed {
+        index: fn(&S) -> usize,
+        values: Vec<Option<(S, T)>>,
+    },
+}
+
+impl<S, T> Default for StateMap<S, T> {
+    fn default() -> Self {
+        StateMap::Hashed(HashMap::default())
+    }
+}
+
+impl<S: Eq + Hash + Clone, T> StateMap<S, T> {
+    pub(crate) fn indexed() -> Self
+    where
+        S: StateIndex,
+    {
+        StateMap::Indexed {
+            index: S::index,
+            values: (0..S::COUNT).map(|_| None).collect(),
+        }
+    }
+
+    pub(crate) fn get(&self, state: &S) -> Option<&T> {
+        match self {
+            StateMap::Hashed(map) => map.get(state),
+            StateMap::Indexed { index, values } => {
+                values[index(state)].as_ref().map(|(_, value)| value)
+            }
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, state: &S) -> Option<&mut T> {
+        match self {
+            StateMap::Hashed(map) => map.get_mut(state),
+            StateMap::Indexed { index, values } => {
+                values[index(state)].as_mut().map(|(_, value)| value)
+            }
+        }
+    }
+
+    pub(crate) fn get_or_insert_with(&mut self, state: S, f: impl FnOnce() -> T) -> &mut T {
+        match self {
+            StateMap::Hashed(map) => map.entry(state).or_insert_with(f),
+            StateMap::Indexed { index, values } => {
+                &mut values[index(&state)].get_or_insert_with(|| (state, f())).1
+            }
+        }
+    }
+
+    pub(crate) fn is_indexed(&self) -> bool {
+        matches!(self, StateMap::Indexed { .. })
+    }
+
+    pub(crate) fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut T> + '_> {
+        match self {
+            StateMap::Hashed(map) => Box::new(map.values_mut()),
+            StateMap::Indexed { values, .. } => {
+                Box::new(values.iter_mut().flatten().map(|(_, value)| value))
+            }
+        }
+    }
+
+    pub(crate) fn keys(&self) -> Box<dyn Iterator<Item = &S> + '_> {
+        match self {
+            StateMap::Hashed(map) => Box::new(map.keys()),
+            StateMap::Indexed { values, .. } => {
+                Box::new(values.iter().flatten().map(|(state, _)| state))
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use bevy::{
+    log::warn,
+    prelude::{App, IntoExclusiveSystem, Plugin, World},
+};
+use std::{fmt, marker::PhantomData};
+
+use crate::{ScheduleState, ScheduleStates};
+
+/// What `StateValidationPlugin` does when it finds a problem
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ValidationFailure {
+    /// log a warning for every problem
+    Warn,
+    /// panic with all the problems
+    Panic,
+}
+
+impl Default for ValidationFailure {
+    fn default() -> Self {
+        ValidationFailure::Warn
+    }
+}
+
+/// A problem with how a state machine was set up
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StateValidationError {
+    /// a state has no enter, update or exit systems and there are no any state systems
+    NoSystems {
+        state_type: &'static str,
+        state: &'static str,
+    },
+    /// a driver for `nested` was nested in `state`, but there is no `ScheduleStates`
+    /// for `nested`, usually because its `StatePlugin` wasn't added
+    MissingNestedStates {
+        state_type: &'static str,
+        state: &'static str,
+        nested: &'static str,
+    },
+}
+
+impl fmt::Display for StateValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateValidationError::NoSystems { state_type, state } => write!(
+                f,
+                "{}::{} has no enter, update or exit systems",
+                state_type, state
+            ),
+            StateValidationError::MissingNestedStates {
+                state_type,
+                state,
+                nested,
+            } => write!(
+                f,
+                "a driver for {} is nested in {}::{}, but StatePlugin<{}> was never added",
+                nested, state_type, state, nested
+            ),
+        }
+    }
+}
+
+/// Checks at startup that every state of `S` has systems and that the drivers nested in
+/// them have a `ScheduleStates`. Add this after `StatePlugin<S>`.
+pub struct StateValidationPlugin<S> {
+    on_failure: ValidationFailure,
+    marker: PhantomData<S>,
+}
+
+impl<S> StateValidationPlugin<S> {
+    pub fn new(on_failure: ValidationFailure) -> Self {
+        StateValidationPlugin {
+            on_failure,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<S> Default for StateValidationPlugin<S> {
+    fn default() -> Self {
+        Self::new(ValidationFailure::default())
+    }
+}
+
+impl<S: ScheduleState> Plugin for StateValidationPlugin<S> {
+    fn build(&self, app: &mut App) {
+        let on_failure = self.on_failure;
+        app.add_startup_system(
+            (move |world: &mut World| report(validate_states::<S>(world), on_failure))
+                .exclusive_system(),
+        );
+    }
+}
+
+fn report(errors: Vec<StateValidationError>, on_failure: ValidationFailure) {
+    if errors.is_empty() {
+        return;
+    }
+    match on_failure {
+        ValidationFailure::Warn => {
+            for error in errors.iter() {
+                warn!("{}", error);
+            }
+        }
+        ValidationFailure::Panic => {
+            let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+            panic!("invalid state machine:\n{}", errors.join("\n"));
+        }
+    }
+}
+
+/// finds the states of `S` without systems and the nested drivers without a
+/// `ScheduleStates`
+pub fn validate_states<S: ScheduleState>(world: &World) -> Vec<StateValidationError> {
+    let state_type = std::any::type_name::<S>();
+    let states = match world.get_resource::<ScheduleStates<S>>() {
+        Some(states) => states,
+        None => return Vec::new(),
+    };
+
+    let any_state_systems = states.any_state_system_count();
+    let mut errors = Vec::new();
+    for state in S::VARIANTS.iter() {
+        if any_state_systems == 0 && states.system_counts(state).iter().sum::<usize>() == 0 {
+            errors.push(StateValidationError::NoSystems {
+                state_type,
+                state: state.name(),
+            });
+        }
+        for nested in states.nested_drivers(state.clone()) {
+            if !nested.exists(world) {
+                errors.push(StateValidationError::MissingNestedStates {
+                    state_type,
+                    state: state.name(),
+                    nested: nested.type_name(),
+                });
+            }
+        }
+    }
+    errors
+}